@@ -0,0 +1,587 @@
+//! Audio decoding and encoding for backends consuming or returning raw audio samples
+pub mod transcription;
+
+use std::f64::consts::PI;
+use std::io::{Cursor, ErrorKind};
+use std::path::Path;
+
+use anyhow::Context;
+use bytes::Bytes;
+use openai_dive::v1::resources::shared::{FileUpload, FileUploadBytes};
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+use crate::errors::AiRouterError;
+use crate::utils::get_file_extension;
+
+/// Overlap of chunks if `chunk_length` is set for a model but `chunk_overlap` is not, in seconds
+pub const DEFAULT_CHUNK_OVERLAP: u32 = 2;
+const WAV_BITS_PER_SAMPLE: u16 = 16;
+const WAV_CHANNELS: u16 = 1;
+/// Sample rate expected by Whisper models
+pub const WHISPER_SAMPLE_RATE: u32 = 16_000;
+/// Zero crossings of the resampling filter on each side of a sample
+const RESAMPLE_ZERO_CROSSINGS: usize = 8;
+/// Precomputed values of the resampling filter per zero crossing
+const RESAMPLE_KERNEL_RESOLUTION: usize = 256;
+
+/// Container formats accepted by `OpenAI` backends
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AudioFormat {
+    Flac,
+    Mp3,
+    Mp4,
+    Ogg,
+    Wav,
+    Webm,
+}
+
+impl AudioFormat {
+    /// Detect the container format from the first bytes of a file
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        match data {
+            [b'f', b'L', b'a', b'C', ..] => Some(Self::Flac),
+            [b'I', b'D', b'3', ..] => Some(Self::Mp3),
+            // MPEG audio frame sync, layer bits of 0 are AAC in ADTS
+            [0xff, b, ..] if b & 0xe0 == 0xe0 && b & 0x06 != 0 => Some(Self::Mp3),
+            [_, _, _, _, b'f', b't', b'y', b'p', ..] => Some(Self::Mp4),
+            [b'O', b'g', b'g', b'S', ..] => Some(Self::Ogg),
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => Some(Self::Wav),
+            [0x1a, 0x45, 0xdf, 0xa3, ..] => Some(Self::Webm),
+            _ => None,
+        }
+    }
+
+    /// File extensions of the format accepted by `OpenAI`, the first one is used for renaming
+    pub fn extensions(self) -> &'static [&'static str] {
+        match self {
+            Self::Flac => &["flac"],
+            Self::Mp3 => &["mp3", "mpeg", "mpga"],
+            Self::Mp4 => &["m4a", "mp4"],
+            Self::Ogg => &["ogg", "oga", "ogm"],
+            Self::Wav => &["wav"],
+            Self::Webm => &["webm"],
+        }
+    }
+}
+
+/// Mono audio samples in the range [-1.0, 1.0]
+#[derive(Debug)]
+pub struct DecodedAudio {
+    pub samples: Vec<f32>,
+    pub sample_rate: u32,
+}
+
+impl DecodedAudio {
+    /// Duration in seconds
+    pub fn duration(&self) -> f64 {
+        self.samples.len() as f64 / f64::from(self.sample_rate)
+    }
+}
+
+/// Decode the first audio track of an audio file, mixing all channels down to mono
+///
+/// The container format is detected from the data, the file extension is only used as a hint.
+///
+/// # Errors
+/// - when the container format or codec is not supported
+/// - when the audio data is malformed
+pub fn decode(data: Bytes, extension: Option<&str>) -> anyhow::Result<DecodedAudio> {
+    let source = MediaSourceStream::new(Box::new(Cursor::new(data)), Default::default());
+
+    let mut hint = Hint::new();
+    if let Some(extension) = extension {
+        hint.with_extension(extension);
+    }
+
+    let probed = symphonia::default::get_probe()
+        .format(
+            &hint,
+            source,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .context("failed to detect audio format")?;
+    let mut format = probed.format;
+
+    let track = format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        .context("no audio track found")?;
+    let track_id = track.id;
+    let sample_rate = track
+        .codec_params
+        .sample_rate
+        .context("unknown sample rate")?;
+
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .context("unsupported audio codec")?;
+
+    let mut samples: Vec<f32> = Vec::new();
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e).context("failed to read audio packet"),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            Err(SymphoniaError::DecodeError(e)) => {
+                tracing::warn!("skipping malformed audio packet: {e}");
+                continue;
+            }
+            Err(e) => return Err(e).context("failed to decode audio packet"),
+        };
+
+        let spec = *decoded.spec();
+        let channels = spec.channels.count();
+        let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+        buffer.copy_interleaved_ref(decoded);
+
+        samples.extend(
+            buffer
+                .samples()
+                .chunks_exact(channels)
+                .map(|frame| frame.iter().sum::<f32>() / channels as f32),
+        );
+    }
+
+    Ok(DecodedAudio {
+        samples,
+        sample_rate,
+    })
+}
+
+/// Decode an uploaded audio file and resample it to `sample_rate`
+///
+/// Decoding long files takes seconds, so it runs on the blocking thread pool.
+///
+/// # Errors
+/// `AiRouterError::BadRequestError` when the file cannot be decoded
+pub async fn decode_upload(
+    file: &FileUpload,
+    sample_rate: u32,
+) -> Result<DecodedAudio, AiRouterError<String>> {
+    let FileUpload::Bytes(file) = file else {
+        return Err(AiRouterError::BadRequestError(String::from(
+            "no audio file uploaded",
+        )));
+    };
+    let bytes = file.bytes.clone();
+    let extension = get_file_extension(&file.filename).ok().map(String::from);
+
+    let audio = tokio::task::spawn_blocking(move || {
+        decode(bytes, extension.as_deref()).map(|audio| resample(audio, sample_rate))
+    })
+    .await?
+    .map_err(|e| AiRouterError::BadRequestError(format!("failed to decode audio: {e:#}")))?;
+
+    Ok(audio)
+}
+
+/// Prepare an uploaded audio file for `OpenAI` backends, see `prepare_upload`
+///
+/// Transcoding long files takes seconds, so it runs on the blocking thread pool.
+///
+/// # Errors
+/// `AiRouterError::BadRequestError` when the format is not supported
+pub async fn prepare_file_upload(file: FileUpload) -> Result<FileUpload, AiRouterError<String>> {
+    match file {
+        FileUpload::Bytes(file) => Ok(FileUpload::Bytes(
+            tokio::task::spawn_blocking(move || prepare_upload(file)).await??,
+        )),
+        file => Ok(file),
+    }
+}
+
+/// Prepare an uploaded audio file for `OpenAI` backends
+///
+/// The format is detected from the data. Files in a format accepted by `OpenAI` are kept, renamed
+/// if their extension does not match the detected format. Other files are decoded and transcoded
+/// to 16 kHz mono WAV.
+///
+/// # Errors
+/// `AiRouterError::BadRequestError` when the format is not supported
+pub fn prepare_upload(file: FileUploadBytes) -> Result<FileUploadBytes, AiRouterError<String>> {
+    let extension = get_file_extension(&file.filename)
+        .ok()
+        .map(str::to_lowercase);
+    let stem = Path::new(&file.filename)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("audio")
+        .to_string();
+
+    if let Some(format) = AudioFormat::detect(&file.bytes) {
+        let extensions = format.extensions();
+        if extension
+            .as_deref()
+            .is_some_and(|extension| extensions.contains(&extension))
+        {
+            return Ok(file);
+        }
+
+        tracing::debug!("renaming {} detected as {format:?}", file.filename);
+        return Ok(FileUploadBytes {
+            bytes: file.bytes,
+            filename: format!("{stem}.{}", extensions[0]),
+        });
+    }
+
+    tracing::debug!("transcoding {} to WAV", file.filename);
+    let audio = decode(file.bytes, extension.as_deref())
+        .map_err(|e| AiRouterError::BadRequestError(format!("unsupported audio format: {e:#}")))?;
+    let audio = resample(audio, WHISPER_SAMPLE_RATE);
+
+    Ok(FileUploadBytes {
+        bytes: Bytes::from(encode_wav(&f32_to_i16(&audio.samples), audio.sample_rate)?),
+        filename: format!("{stem}.wav"),
+    })
+}
+
+/// Part of a longer audio, see `split`
+#[derive(Debug)]
+pub struct AudioChunk {
+    pub audio: DecodedAudio,
+    /// Start of the chunk in the complete audio, in seconds
+    pub offset: f64,
+    /// Start of the part of the chunk not shared with the previous chunk, in seconds
+    pub keep_from: f64,
+    /// End of the part of the chunk not shared with the next chunk, in seconds
+    pub keep_to: f64,
+}
+
+/// Split audio into chunks of `length` seconds overlapping by `overlap` seconds
+///
+/// The shared part of two chunks is divided in the middle, so every point in time is kept in
+/// exactly one chunk.
+pub fn split(audio: &DecodedAudio, length: u32, overlap: u32) -> Vec<AudioChunk> {
+    let rate = audio.sample_rate as usize;
+    let chunk_len = (length as usize * rate).max(1);
+    let step = (length.saturating_sub(overlap) as usize * rate).max(1);
+    let half_overlap = f64::from(overlap) / 2.0;
+    let total = audio.samples.len();
+
+    let mut chunks: Vec<AudioChunk> = Vec::new();
+    let mut start: usize = 0;
+    loop {
+        let end = (start + chunk_len).min(total);
+        let offset = start as f64 / f64::from(audio.sample_rate);
+        let is_last = end == total;
+
+        chunks.push(AudioChunk {
+            audio: DecodedAudio {
+                samples: audio.samples[start..end].to_vec(),
+                sample_rate: audio.sample_rate,
+            },
+            offset,
+            keep_from: if start == 0 {
+                0.0
+            } else {
+                offset + half_overlap
+            },
+            keep_to: if is_last {
+                f64::INFINITY
+            } else {
+                end as f64 / f64::from(audio.sample_rate) - half_overlap
+            },
+        });
+
+        if is_last {
+            break;
+        }
+        start += step;
+    }
+
+    chunks
+}
+
+/// Resample audio using a windowed sinc filter
+///
+/// When downsampling, the filter cuts off at the Nyquist frequency of the new sample rate, so
+/// higher frequencies are removed instead of aliasing into the audible range.
+pub fn resample(audio: DecodedAudio, sample_rate: u32) -> DecodedAudio {
+    if audio.sample_rate == sample_rate || audio.samples.is_empty() {
+        return DecodedAudio {
+            samples: audio.samples,
+            sample_rate,
+        };
+    }
+
+    let ratio = f64::from(audio.sample_rate) / f64::from(sample_rate);
+    // relative to the Nyquist frequency of the original sample rate
+    let cutoff = ratio.recip().min(1.0);
+    let half_width = RESAMPLE_ZERO_CROSSINGS as f64 / cutoff;
+    let last = audio.samples.len() - 1;
+    let len = (audio.samples.len() as f64 / ratio).round() as usize;
+    let kernel = sinc_kernel();
+
+    let samples = (0..len)
+        .map(|i| {
+            let position = i as f64 * ratio;
+            let first = (position - half_width).ceil().max(0.0) as usize;
+            let end = ((position + half_width).floor() as usize).min(last);
+
+            let sum: f64 = (first..=end)
+                .map(|k| {
+                    let x = (k as f64 - position).abs() * cutoff;
+                    f64::from(audio.samples[k]) * kernel_value(&kernel, x)
+                })
+                .sum();
+            (sum * cutoff) as f32
+        })
+        .collect();
+
+    DecodedAudio {
+        samples,
+        sample_rate,
+    }
+}
+
+/// Blackman-windowed sinc from 0 to `RESAMPLE_ZERO_CROSSINGS`, `RESAMPLE_KERNEL_RESOLUTION` values
+/// per zero crossing
+fn sinc_kernel() -> Vec<f64> {
+    let len = RESAMPLE_ZERO_CROSSINGS * RESAMPLE_KERNEL_RESOLUTION + 1;
+    let zero_crossings = RESAMPLE_ZERO_CROSSINGS as f64;
+
+    (0..len)
+        .map(|i| {
+            let x = i as f64 / RESAMPLE_KERNEL_RESOLUTION as f64;
+            let sinc = if i == 0 {
+                1.0
+            } else {
+                (PI * x).sin() / (PI * x)
+            };
+            let window = 0.08f64.mul_add((2.0 * PI * x / zero_crossings).cos(), 0.42)
+                + 0.5 * (PI * x / zero_crossings).cos();
+            sinc * window
+        })
+        .collect()
+}
+
+/// Value of the kernel at `x` zero crossings from the center, interpolating between table values
+fn kernel_value(kernel: &[f64], x: f64) -> f64 {
+    let position = x * RESAMPLE_KERNEL_RESOLUTION as f64;
+    let idx = position as usize;
+    if idx + 1 >= kernel.len() {
+        return 0.0;
+    }
+
+    let fraction = position - idx as f64;
+    (kernel[idx + 1] - kernel[idx]).mul_add(fraction, kernel[idx])
+}
+
+/// Convert samples in the range [-1.0, 1.0] to signed 16-bit samples, clipping out of range values
+pub fn f32_to_i16(samples: &[f32]) -> Vec<i16> {
+    samples
+        .iter()
+        // clamped, so the cast can not overflow
+        .map(|s| (s.clamp(-1.0, 1.0) * f32::from(i16::MAX)) as i16)
+        .collect()
+}
+
+/// Encode mono signed 16-bit samples as raw little-endian PCM
+pub fn encode_pcm(samples: &[i16]) -> Vec<u8> {
+    samples.iter().flat_map(|s| s.to_le_bytes()).collect()
+}
+
+/// Encode mono signed 16-bit samples as WAV
+///
+/// # Errors
+/// - when the encoded audio exceeds the maximum size of a WAV file
+pub fn encode_wav(samples: &[i16], sample_rate: u32) -> anyhow::Result<Vec<u8>> {
+    let block_align = WAV_CHANNELS * WAV_BITS_PER_SAMPLE / 8;
+    let byte_rate = sample_rate * u32::from(block_align);
+    let data_size = u32::try_from(samples.len() * usize::from(block_align))?;
+    let riff_size = data_size
+        .checked_add(36)
+        .ok_or_else(|| anyhow::anyhow!("audio too large for WAV"))?;
+
+    let mut wav: Vec<u8> = Vec::with_capacity(44 + samples.len() * 2);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&riff_size.to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16_u32.to_le_bytes());
+    // PCM
+    wav.extend_from_slice(&1_u16.to_le_bytes());
+    wav.extend_from_slice(&WAV_CHANNELS.to_le_bytes());
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    wav.extend_from_slice(&byte_rate.to_le_bytes());
+    wav.extend_from_slice(&block_align.to_le_bytes());
+    wav.extend_from_slice(&WAV_BITS_PER_SAMPLE.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_size.to_le_bytes());
+    wav.append(&mut encode_pcm(samples));
+
+    Ok(wav)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_f32_to_i16() {
+        assert_eq!(
+            f32_to_i16(&[0.0, 1.0, -1.0, 2.0, -2.0]),
+            [0, i16::MAX, -i16::MAX, i16::MAX, -i16::MAX]
+        );
+    }
+
+    #[test]
+    fn test_decode() {
+        let samples: Vec<i16> = (0..1600).map(|i| (i % 100) * 100).collect();
+        let wav = encode_wav(&samples, 8_000).expect("failed to encode WAV");
+
+        let audio = decode(Bytes::from(wav), Some("wav")).expect("failed to decode WAV");
+        assert_eq!(audio.sample_rate, 8_000);
+        assert_eq!(audio.samples.len(), 1600);
+        assert!((audio.duration() - 0.2).abs() < f64::EPSILON);
+
+        let audio = resample(audio, 16_000);
+        assert_eq!(audio.sample_rate, 16_000);
+        assert_eq!(audio.samples.len(), 3200);
+
+        assert!(decode(Bytes::from_static(b"not audio"), Some("wav")).is_err());
+    }
+
+    fn sine(frequency: f64, sample_rate: u32) -> DecodedAudio {
+        DecodedAudio {
+            samples: (0..sample_rate)
+                .map(|i| {
+                    (2.0 * PI * frequency * f64::from(i) / f64::from(sample_rate)).sin() as f32
+                })
+                .collect(),
+            sample_rate,
+        }
+    }
+
+    /// Root mean square of the samples, leaving out the edges of the filter
+    fn rms(audio: &DecodedAudio) -> f64 {
+        let samples = &audio.samples[1000..audio.samples.len() - 1000];
+        let sum: f64 = samples.iter().map(|s| f64::from(*s).powi(2)).sum();
+        (sum / samples.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn test_resample() {
+        // below the new Nyquist frequency of 8 kHz, a sine keeps its amplitude
+        let audio = resample(sine(1_000.0, 48_000), WHISPER_SAMPLE_RATE);
+        assert_eq!(audio.samples.len(), 16_000);
+        assert!((rms(&audio) - 0.5_f64.sqrt()).abs() < 0.01);
+
+        let audio = resample(sine(3_000.0, 44_100), WHISPER_SAMPLE_RATE);
+        assert!((rms(&audio) - 0.5_f64.sqrt()).abs() < 0.01);
+
+        // above it, it is filtered out instead of aliasing to 4 kHz
+        let audio = resample(sine(12_000.0, 48_000), WHISPER_SAMPLE_RATE);
+        assert!(rms(&audio) < 0.01);
+
+        let audio = resample(sine(1_000.0, 8_000), WHISPER_SAMPLE_RATE);
+        assert_eq!(audio.samples.len(), 16_000);
+        assert!((rms(&audio) - 0.5_f64.sqrt()).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_detect_format() {
+        let wav = encode_wav(&[0; 16], 8_000).expect("failed to encode WAV");
+
+        assert_eq!(AudioFormat::detect(&wav), Some(AudioFormat::Wav));
+        assert_eq!(AudioFormat::detect(b"fLaC\0\0"), Some(AudioFormat::Flac));
+        assert_eq!(AudioFormat::detect(b"ID3\x04"), Some(AudioFormat::Mp3));
+        assert_eq!(
+            AudioFormat::detect(&[0xff, 0xfb, 0x90]),
+            Some(AudioFormat::Mp3)
+        );
+        // AAC in ADTS
+        assert_eq!(AudioFormat::detect(&[0xff, 0xf1, 0x50]), None);
+        assert_eq!(
+            AudioFormat::detect(b"\0\0\0\x20ftypM4A "),
+            Some(AudioFormat::Mp4)
+        );
+        assert_eq!(AudioFormat::detect(b"OggS\0"), Some(AudioFormat::Ogg));
+        assert_eq!(
+            AudioFormat::detect(&[0x1a, 0x45, 0xdf, 0xa3]),
+            Some(AudioFormat::Webm)
+        );
+        assert_eq!(AudioFormat::detect(b"not audio"), None);
+    }
+
+    #[test]
+    fn test_prepare_upload() {
+        let wav = Bytes::from(encode_wav(&[0; 16], 8_000).expect("failed to encode WAV"));
+        let upload = |filename: &str, bytes: &Bytes| {
+            prepare_upload(FileUploadBytes {
+                bytes: bytes.clone(),
+                filename: String::from(filename),
+            })
+        };
+
+        let file = upload("speech.WAV", &wav).expect("failed to prepare WAV");
+        assert_eq!(file.filename, "speech.WAV");
+        assert_eq!(file.bytes, wav);
+
+        // mislabeled files are renamed
+        let file = upload("speech.mp3", &wav).expect("failed to prepare WAV");
+        assert_eq!(file.filename, "speech.wav");
+        let file = upload("speech", &wav).expect("failed to prepare WAV");
+        assert_eq!(file.filename, "speech.wav");
+
+        assert!(matches!(
+            upload("speech.wav", &Bytes::from_static(b"not audio")),
+            Err(AiRouterError::BadRequestError(_))
+        ));
+    }
+
+    #[test]
+    fn test_split() {
+        let audio = DecodedAudio {
+            samples: vec![0.0; 25],
+            sample_rate: 1,
+        };
+
+        let chunks = split(&audio, 10, 2);
+        let bounds: Vec<(usize, f64, f64, f64)> = chunks
+            .iter()
+            .map(|c| (c.audio.samples.len(), c.offset, c.keep_from, c.keep_to))
+            .collect();
+
+        assert_eq!(
+            bounds,
+            [
+                (10, 0.0, 0.0, 9.0),
+                (10, 8.0, 9.0, 17.0),
+                (9, 16.0, 17.0, f64::INFINITY)
+            ]
+        );
+
+        assert_eq!(split(&audio, 30, 2).len(), 1);
+    }
+
+    #[test]
+    fn test_encode_wav() {
+        let wav = encode_wav(&[0, 1, -1], 24_000).expect("failed to encode WAV");
+
+        assert_eq!(wav.len(), 44 + 6);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(&wav[4..8], &42_u32.to_le_bytes());
+        assert_eq!(&wav[8..12], b"WAVE");
+        assert_eq!(&wav[24..28], &24_000_u32.to_le_bytes());
+        assert_eq!(&wav[28..32], &48_000_u32.to_le_bytes());
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(&wav[40..44], &6_u32.to_le_bytes());
+        assert_eq!(&wav[44..], [0, 0, 1, 0, 0xff, 0xff]);
+    }
+}
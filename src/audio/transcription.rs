@@ -0,0 +1,573 @@
+//! Transcriptions in the response formats of the `OpenAI` audio transcriptions API
+use std::future::Future;
+
+use async_stream::try_stream;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures::stream::{self, Stream, StreamExt, TryStreamExt};
+use openai_dive::v1::resources::audio::AudioOutputFormat;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::audio::{split, AudioChunk, DecodedAudio};
+use crate::errors::AiRouterError;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TranscriptionTask {
+    #[default]
+    Transcribe,
+    Translate,
+}
+
+/// Transcription in `verbose_json` format
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Transcription {
+    #[serde(default)]
+    pub task: TranscriptionTask,
+    #[serde(default)]
+    pub language: String,
+    #[serde(default)]
+    pub duration: f64,
+    pub text: String,
+    #[serde(default)]
+    pub segments: Vec<TranscriptionSegment>,
+}
+
+/// Server-sent event of a streaming transcription
+///
+/// <https://platform.openai.com/docs/api-reference/audio/transcript-text-delta-event>
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type")]
+pub enum TranscriptionStreamEvent {
+    #[serde(rename = "transcript.text.delta")]
+    Delta { delta: String },
+    #[serde(rename = "transcript.text.done")]
+    Done { text: String },
+}
+
+/// Segment of a transcription, fields Whisper backends do not return are left at zero
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TranscriptionSegment {
+    pub id: usize,
+    #[serde(default)]
+    pub seek: usize,
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+    #[serde(default)]
+    pub tokens: Vec<u32>,
+    #[serde(default)]
+    pub temperature: f64,
+    #[serde(default)]
+    pub avg_logprob: f64,
+    #[serde(default)]
+    pub compression_ratio: f64,
+    #[serde(default)]
+    pub no_speech_prob: f64,
+}
+
+impl Transcription {
+    /// Parse the output of a Whisper model
+    ///
+    /// Text between two timestamp tokens like `<|0.00|> Hello.<|1.20|>` becomes a segment, other
+    /// special tokens are dropped. Text without timestamps becomes a single segment covering the
+    /// whole audio.
+    pub fn from_whisper_output(
+        output: &str,
+        task: TranscriptionTask,
+        language: Option<&str>,
+        duration: f64,
+    ) -> Self {
+        let mut detected_language: Option<&str> = None;
+        let mut segments: Vec<TranscriptionSegment> = Vec::new();
+        let mut start: Option<f64> = None;
+        let mut end: f64 = 0.0;
+        let mut text = String::new();
+        let mut rest = output;
+
+        let mut push_segment = |start: f64, end: f64, text: &mut String| {
+            if !text.trim().is_empty() {
+                segments.push(TranscriptionSegment {
+                    id: segments.len(),
+                    start,
+                    end,
+                    text: text.clone(),
+                    ..Default::default()
+                });
+            }
+            text.clear();
+        };
+
+        while let Some(token_start) = rest.find("<|") {
+            text.push_str(&rest[..token_start]);
+            let Some(token_len) = rest[token_start + 2..].find("|>") else {
+                rest = &rest[token_start..];
+                break;
+            };
+            let token = &rest[token_start + 2..token_start + 2 + token_len];
+            rest = &rest[token_start + token_len + 4..];
+
+            if let Ok(timestamp) = token.parse::<f64>() {
+                match start.take() {
+                    Some(start) => push_segment(start, timestamp, &mut text),
+                    None if !text.trim().is_empty() => push_segment(end, timestamp, &mut text),
+                    None => start = Some(timestamp),
+                }
+                end = timestamp;
+            } else if detected_language.is_none() && is_language_token(token) {
+                detected_language = Some(token);
+            }
+        }
+        text.push_str(rest);
+        push_segment(start.unwrap_or(end), duration.max(end), &mut text);
+
+        Self {
+            task,
+            language: String::from(language.or(detected_language).unwrap_or("en")),
+            duration,
+            text: join_segments(&segments),
+            segments,
+        }
+    }
+
+    /// Format the transcription as `srt` subtitles
+    pub fn to_srt(&self) -> String {
+        self.segments
+            .iter()
+            .enumerate()
+            .map(|(i, s)| {
+                format!(
+                    "{}\n{} --> {}\n{}\n\n",
+                    i + 1,
+                    format_timestamp(s.start, ','),
+                    format_timestamp(s.end, ','),
+                    s.text.trim()
+                )
+            })
+            .collect()
+    }
+
+    /// Format the transcription as `vtt` subtitles
+    pub fn to_vtt(&self) -> String {
+        let mut vtt = String::from("WEBVTT\n\n");
+
+        for s in &self.segments {
+            vtt.push_str(&format!(
+                "{} --> {}\n{}\n\n",
+                format_timestamp(s.start, '.'),
+                format_timestamp(s.end, '.'),
+                s.text.trim()
+            ));
+        }
+
+        vtt
+    }
+
+    /// Build the response for the `response_format` of the request, `json` if unset
+    pub fn into_response(self, response_format: Option<&AudioOutputFormat>) -> Response {
+        match response_format {
+            None | Some(AudioOutputFormat::Json) => {
+                Json(json!({ "text": self.text })).into_response()
+            }
+            Some(AudioOutputFormat::Text) => self.text.into_response(),
+            Some(AudioOutputFormat::Srt) => self.to_srt().into_response(),
+            Some(AudioOutputFormat::Vtt) => self.to_vtt().into_response(),
+            Some(AudioOutputFormat::VerboseJson) => Json(self).into_response(),
+        }
+    }
+
+    /// Events of a streaming response for a transcription that is already complete
+    pub fn into_stream_events(self) -> Vec<TranscriptionStreamEvent> {
+        let mut events: Vec<TranscriptionStreamEvent> = Vec::new();
+
+        if !self.text.is_empty() {
+            events.push(TranscriptionStreamEvent::Delta {
+                delta: self.text.clone(),
+            });
+        }
+        events.push(TranscriptionStreamEvent::Done { text: self.text });
+
+        events
+    }
+}
+
+/// Number of chunks transcribed concurrently by `transcribe_chunked`
+const MAX_CONCURRENT_CHUNKS: usize = 4;
+
+/// Transcribe long audio in overlapping chunks
+///
+/// Chunks are transcribed concurrently using `transcribe`, which must return a transcription with
+/// segments. Segments are shifted to the position of their chunk in the complete audio, and
+/// segments in the overlap of two chunks are kept from the chunk containing their midpoint.
+///
+/// # Errors
+/// - when transcribing any of the chunks fails
+pub async fn transcribe_chunked<F, Fut>(
+    audio: &DecodedAudio,
+    length: u32,
+    overlap: u32,
+    transcribe: F,
+) -> Result<Transcription, AiRouterError<String>>
+where
+    F: FnMut(DecodedAudio) -> Fut,
+    Fut: Future<Output = Result<Transcription, AiRouterError<String>>>,
+{
+    let transcriptions: Vec<Transcription> = transcribe_chunks(audio, length, overlap, transcribe)
+        .try_collect()
+        .await?;
+
+    let mut merged = Transcription {
+        duration: audio.duration(),
+        ..Default::default()
+    };
+
+    for transcription in transcriptions {
+        if merged.language.is_empty() {
+            merged.language = transcription.language;
+            merged.task = transcription.task;
+        }
+
+        for mut segment in transcription.segments {
+            segment.id = merged.segments.len();
+            merged.segments.push(segment);
+        }
+    }
+
+    merged.text = join_segments(&merged.segments);
+
+    Ok(merged)
+}
+
+/// Transcribe long audio in overlapping chunks like `transcribe_chunked`, streaming the text of
+/// every chunk as soon as it and all previous chunks are transcribed
+pub fn transcribe_chunked_stream<F, Fut>(
+    audio: &DecodedAudio,
+    length: u32,
+    overlap: u32,
+    transcribe: F,
+) -> impl Stream<Item = Result<TranscriptionStreamEvent, AiRouterError<String>>>
+where
+    F: FnMut(DecodedAudio) -> Fut,
+    Fut: Future<Output = Result<Transcription, AiRouterError<String>>>,
+{
+    let mut transcriptions = Box::pin(transcribe_chunks(audio, length, overlap, transcribe));
+
+    try_stream! {
+        let mut text = String::new();
+
+        while let Some(transcription) = transcriptions.next().await {
+            let delta: String = transcription?
+                .segments
+                .iter()
+                .map(|s| s.text.as_str())
+                .collect();
+            let delta = if text.is_empty() {
+                delta.trim_start().to_string()
+            } else {
+                delta
+            };
+            if delta.is_empty() {
+                continue;
+            }
+
+            text.push_str(&delta);
+            yield TranscriptionStreamEvent::Delta { delta };
+        }
+
+        yield TranscriptionStreamEvent::Done {
+            text: text.trim_end().to_string(),
+        };
+    }
+}
+
+/// Transcribe the chunks of `audio` concurrently, returning the transcriptions in order with only
+/// the segments kept from each chunk, shifted to their position in the complete audio
+fn transcribe_chunks<F, Fut>(
+    audio: &DecodedAudio,
+    length: u32,
+    overlap: u32,
+    mut transcribe: F,
+) -> impl Stream<Item = Result<Transcription, AiRouterError<String>>>
+where
+    F: FnMut(DecodedAudio) -> Fut,
+    Fut: Future<Output = Result<Transcription, AiRouterError<String>>>,
+{
+    let chunks = split(audio, length, overlap);
+    tracing::debug!("transcribing audio in {} chunks", chunks.len());
+
+    stream::iter(chunks)
+        .map(move |chunk| {
+            let AudioChunk {
+                audio,
+                offset,
+                keep_from,
+                keep_to,
+            } = chunk;
+            let duration = audio.duration();
+            let transcription = transcribe(audio);
+            async move {
+                let mut transcription = transcription.await?;
+                if transcription.segments.is_empty() && !transcription.text.trim().is_empty() {
+                    transcription.segments.push(TranscriptionSegment {
+                        end: duration,
+                        text: format!(" {}", transcription.text.trim()),
+                        ..Default::default()
+                    });
+                }
+
+                transcription.segments = transcription
+                    .segments
+                    .into_iter()
+                    .filter_map(|mut segment| {
+                        segment.start += offset;
+                        segment.end += offset;
+
+                        let midpoint = (segment.start + segment.end) / 2.0;
+                        (midpoint >= keep_from && midpoint < keep_to).then_some(segment)
+                    })
+                    .collect();
+
+                Ok(transcription)
+            }
+        })
+        .buffered(MAX_CONCURRENT_CHUNKS)
+}
+
+/// Build a streaming response from transcription events
+///
+/// The first event is awaited before responding, so a failing backend results in an error
+/// response instead of a stream with an error event.
+///
+/// # Errors
+/// - when the first event is an error
+pub async fn stream_response<S>(events: S) -> Result<Response, AiRouterError<String>>
+where
+    S: Stream<Item = Result<TranscriptionStreamEvent, AiRouterError<String>>> + Send + 'static,
+{
+    let mut events = Box::pin(events);
+    let first = events.next().await.transpose()?;
+    let events = stream::iter(first.map(Ok)).chain(events);
+
+    Ok(Sse::new(sse_events(events))
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+fn sse_events<S>(events: S) -> impl Stream<Item = anyhow::Result<Event>>
+where
+    S: Stream<Item = Result<TranscriptionStreamEvent, AiRouterError<String>>>,
+{
+    let mut events = Box::pin(events);
+
+    try_stream! {
+        while let Some(event) = events.next().await {
+            match event {
+                Ok(event) => yield Event::default().json_data(event)?,
+                Err(e) => {
+                    tracing::error!("failed to transcribe audio: {e:?}");
+
+                    yield Event::default().event("error").json_data(json!({
+                        "error": {
+                            "status_code": 500,
+                            "message": "Internal Server Error"
+                        }
+                    }))?;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Join the text of segments into the text of a transcription
+pub fn join_segments(segments: &[TranscriptionSegment]) -> String {
+    segments
+        .iter()
+        .map(|s| s.text.as_str())
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+/// Language tokens of Whisper are ISO 639-1 codes, or 3 letters for a few languages
+fn is_language_token(token: &str) -> bool {
+    (2..=3).contains(&token.len()) && token.chars().all(|c| c.is_ascii_lowercase())
+}
+
+/// Format seconds as `HH:MM:SS,mmm`, with `separator` before the milliseconds
+fn format_timestamp(seconds: f64, separator: char) -> String {
+    let millis = (seconds.max(0.0) * 1000.0).round() as u64;
+
+    format!(
+        "{:02}:{:02}:{:02}{separator}{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHISPER_OUTPUT: &str = "<|startoftranscript|><|de|><|transcribe|><|0.00|> Hallo Welt.<|1.50|><|1.50|> Wie geht's?<|3.20|><|endoftext|>";
+
+    #[test]
+    fn test_from_whisper_output() {
+        let transcription = Transcription::from_whisper_output(
+            WHISPER_OUTPUT,
+            TranscriptionTask::Transcribe,
+            None,
+            3.5,
+        );
+
+        assert_eq!(transcription.language, "de");
+        assert_eq!(transcription.text, "Hallo Welt. Wie geht's?");
+        assert_eq!(transcription.segments.len(), 2);
+        assert_eq!(transcription.segments[1].id, 1);
+        assert!((transcription.segments[1].start - 1.5).abs() < f64::EPSILON);
+        assert!((transcription.segments[1].end - 3.2).abs() < f64::EPSILON);
+
+        let transcription = Transcription::from_whisper_output(
+            "<|notimestamps|> Hello world.<|endoftext|>",
+            TranscriptionTask::Transcribe,
+            Some("en"),
+            2.0,
+        );
+
+        assert_eq!(transcription.text, "Hello world.");
+        assert_eq!(transcription.segments.len(), 1);
+        assert!((transcription.segments[0].end - 2.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_subtitles() {
+        let transcription = Transcription::from_whisper_output(
+            WHISPER_OUTPUT,
+            TranscriptionTask::Transcribe,
+            None,
+            3.5,
+        );
+
+        assert_eq!(
+            transcription.to_srt(),
+            "1\n00:00:00,000 --> 00:00:01,500\nHallo Welt.\n\n2\n00:00:01,500 --> 00:00:03,200\nWie geht's?\n\n"
+        );
+        assert_eq!(
+            transcription.to_vtt(),
+            "WEBVTT\n\n00:00:00.000 --> 00:00:01.500\nHallo Welt.\n\n00:00:01.500 --> 00:00:03.200\nWie geht's?\n\n"
+        );
+    }
+
+    /// Transcription of a chunk with a segment in each overlap and one in between
+    fn chunk_transcription(duration: f64) -> Transcription {
+        Transcription {
+            language: String::from("en"),
+            text: String::from("a b c"),
+            segments: vec![
+                TranscriptionSegment {
+                    start: 0.0,
+                    end: 1.0,
+                    text: String::from(" a"),
+                    ..Default::default()
+                },
+                TranscriptionSegment {
+                    start: 1.0,
+                    end: duration - 1.0,
+                    text: String::from(" b"),
+                    ..Default::default()
+                },
+                TranscriptionSegment {
+                    start: duration - 1.0,
+                    end: duration,
+                    text: String::from(" c"),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transcribe_chunked() {
+        let audio = DecodedAudio {
+            samples: vec![0.0; 25],
+            sample_rate: 1,
+        };
+
+        let transcription = transcribe_chunked(&audio, 10, 2, |chunk| {
+            let duration = chunk.duration();
+            async move { Ok(chunk_transcription(duration)) }
+        })
+        .await
+        .expect("failed to transcribe chunks");
+
+        let segments: Vec<(usize, f64, f64)> = transcription
+            .segments
+            .iter()
+            .map(|s| (s.id, s.start, s.end))
+            .collect();
+
+        assert_eq!(transcription.language, "en");
+        // segments in overlaps are only kept once
+        assert_eq!(transcription.text, "a b b b c");
+        assert_eq!(
+            segments,
+            [
+                (0, 0.0, 1.0),
+                (1, 1.0, 9.0),
+                (2, 9.0, 17.0),
+                (3, 17.0, 24.0),
+                (4, 24.0, 25.0)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transcribe_chunked_stream() {
+        let audio = DecodedAudio {
+            samples: vec![0.0; 25],
+            sample_rate: 1,
+        };
+
+        let events: Vec<TranscriptionStreamEvent> =
+            transcribe_chunked_stream(&audio, 10, 2, |chunk| {
+                let duration = chunk.duration();
+                async move { Ok(chunk_transcription(duration)) }
+            })
+            .try_collect()
+            .await
+            .expect("failed to transcribe chunks");
+
+        assert_eq!(
+            events,
+            [
+                TranscriptionStreamEvent::Delta {
+                    delta: String::from("a b")
+                },
+                TranscriptionStreamEvent::Delta {
+                    delta: String::from(" b")
+                },
+                TranscriptionStreamEvent::Delta {
+                    delta: String::from(" b c")
+                },
+                TranscriptionStreamEvent::Done {
+                    text: String::from("a b b b c")
+                },
+            ]
+        );
+        assert_eq!(
+            serde_json::to_string(&events[0]).expect("failed to serialize event"),
+            r#"{"type":"transcript.text.delta","delta":"a b"}"#
+        );
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(3725.042, ','), "01:02:05,042");
+        assert_eq!(format_timestamp(-1.0, '.'), "00:00:00.000");
+    }
+}
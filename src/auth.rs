@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::extract::{Request, State as AxumState};
+use axum::http::header::AUTHORIZATION;
+use axum::http::HeaderMap;
+use axum::middleware::Next;
+use axum::response::Response;
+use subtle::ConstantTimeEq;
+
+use crate::config::{AiRouterApiKey, AiRouterConfigFile, AiRouterModelType};
+use crate::errors::AiRouterError;
+use crate::models::{glob_match, is_pattern};
+use crate::state::State;
+
+/// Permissions of the API key used in a client request
+///
+/// Inserted into the request extensions by `require_api_key`. Keys configured in `daemon.api_key`
+/// and requests to a router without API keys can use all models.
+#[derive(Clone, Default)]
+pub struct ApiKeyAccess {
+    pub name: Option<String>,
+    pub owner: Option<String>,
+    pub models: Option<Vec<String>>,
+    pub model_types: Option<Vec<AiRouterModelType>>,
+    /// API keys to use for `OpenAI` backends without `api_key`, indexed by backend name
+    pub backend_api_keys: Option<HashMap<String, String>>,
+    /// API key sent by the client, only set when the router does not require API keys
+    pub client_api_key: Option<String>,
+}
+
+impl ApiKeyAccess {
+    fn from_api_key(name: &str, api_key: &AiRouterApiKey) -> Self {
+        Self {
+            name: Some(String::from(name)),
+            owner: api_key.owner.clone(),
+            models: api_key.models.clone(),
+            model_types: api_key.model_types.clone(),
+            backend_api_keys: api_key.backend_api_keys.clone(),
+            client_api_key: None,
+        }
+    }
+
+    /// Get the API key to pass to an `OpenAI` backend that has no API key configured
+    ///
+    /// The API key mapped to the backend takes precedence over the API key sent by the client.
+    pub fn backend_api_key(&self, backend_name: &str) -> Option<&str> {
+        self.backend_api_keys
+            .as_ref()
+            .and_then(|k| k.get(backend_name))
+            .or(self.client_api_key.as_ref())
+            .map(String::as_str)
+    }
+
+    /// Check if the API key is allowed to use the model
+    ///
+    /// When both `models` and `model_types` are set, the model needs to match both. Glob patterns
+    /// in `models`, e.g. the name of a pattern model, allow all models they match.
+    pub fn can_use(&self, model_type: &AiRouterModelType, model_name: &str) -> bool {
+        if let Some(model_types) = &self.model_types {
+            if !model_types.contains(model_type) {
+                return false;
+            }
+        }
+
+        if let Some(models) = &self.models {
+            if !models
+                .iter()
+                .any(|m| m == model_name || (is_pattern(m) && glob_match(m, model_name).is_some()))
+            {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+impl fmt::Debug for ApiKeyAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ApiKeyAccess")
+            .field("name", &self.name)
+            .field("owner", &self.owner)
+            .field("models", &self.models)
+            .field("model_types", &self.model_types)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Reject requests without a valid API key in the `Authorization` header
+///
+/// When no API keys are configured in `daemon.api_key` or `api_keys`, all requests are accepted.
+///
+/// # Errors
+/// `AiRouterError::InvalidApiKey` when the API key is missing, unknown or expired
+pub async fn require_api_key(
+    AxumState(state): AxumState<Arc<State>>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AiRouterError<String>> {
+    if state.config.daemon.api_key.is_empty() && state.config.api_keys.is_empty() {
+        let access = ApiKeyAccess {
+            client_api_key: get_bearer_token(request.headers()).map(String::from),
+            ..ApiKeyAccess::default()
+        };
+        request.extensions_mut().insert(access);
+        return Ok(next.run(request).await);
+    }
+
+    let Some(api_key) = get_bearer_token(request.headers()) else {
+        return Err(AiRouterError::InvalidApiKey(String::from(
+            "You didn't provide an API key. You need to provide your API key in an Authorization header using Bearer auth (i.e. Authorization: Bearer YOUR_KEY).",
+        )));
+    };
+
+    let access = find_api_key(&state.config, api_key, unix_timestamp()?)?;
+    tracing::debug!(
+        "request authorized with API key {:?} owned by {:?}",
+        access.name,
+        access.owner
+    );
+
+    request.extensions_mut().insert(access);
+
+    Ok(next.run(request).await)
+}
+
+/// Look up the permissions of an API key
+fn find_api_key(
+    config: &AiRouterConfigFile,
+    api_key: &str,
+    now: u64,
+) -> Result<ApiKeyAccess, AiRouterError<String>> {
+    if config.daemon.api_key.iter().any(|k| keys_match(k, api_key)) {
+        return Ok(ApiKeyAccess::default());
+    }
+
+    let Some((name, configured)) = config
+        .api_keys
+        .iter()
+        .find(|(_, k)| keys_match(&k.key, api_key))
+    else {
+        return Err(AiRouterError::InvalidApiKey(format!(
+            "Incorrect API key provided: {}.",
+            redact_api_key(api_key)
+        )));
+    };
+
+    if configured
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= now)
+    {
+        return Err(AiRouterError::InvalidApiKey(format!(
+            "API key {} has expired.",
+            redact_api_key(api_key)
+        )));
+    }
+
+    Ok(ApiKeyAccess::from_api_key(name, configured))
+}
+
+/// Compare API keys in constant time, so response times don't reveal how much of a key is correct
+fn keys_match(configured: &str, api_key: &str) -> bool {
+    configured.as_bytes().ct_eq(api_key.as_bytes()).into()
+}
+
+fn unix_timestamp() -> Result<u64, AiRouterError<String>> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// Get the token from an `Authorization: Bearer <token>` header
+fn get_bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+
+    let token = token.trim();
+    if token.is_empty() {
+        return None;
+    }
+
+    Some(token)
+}
+
+/// Only keep the first and last 4 characters of an API key so it can be used in error messages
+fn redact_api_key(api_key: &str) -> String {
+    let chars: Vec<char> = api_key.chars().collect();
+
+    if chars.len() <= 8 {
+        return String::from("****");
+    }
+
+    let head: String = chars[..4].iter().collect();
+    let tail: String = chars[chars.len() - 4..].iter().collect();
+
+    format!("{head}****{tail}")
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn test_get_bearer_token() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(get_bearer_token(&headers), None);
+
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer my_key"));
+        assert_eq!(get_bearer_token(&headers), Some("my_key"));
+
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("bearer my_key"));
+        assert_eq!(get_bearer_token(&headers), Some("my_key"));
+
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Basic bXk6a2V5"));
+        assert_eq!(get_bearer_token(&headers), None);
+
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer "));
+        assert_eq!(get_bearer_token(&headers), None);
+    }
+
+    #[test]
+    fn test_find_api_key() {
+        let config = AiRouterConfigFile::parse(String::from("tests/ai-router.toml.api_keys"))
+            .expect("failed to load test config file");
+
+        let access = find_api_key(&config, "simple_key", 0).expect("simple key rejected");
+        assert!(access.name.is_none());
+        assert!(access.can_use(&AiRouterModelType::Embeddings, "embed"));
+
+        let access = find_api_key(&config, "team_a_key", 0).expect("team_a key rejected");
+        assert_eq!(access.name.as_deref(), Some("team_a"));
+        assert!(access.can_use(&AiRouterModelType::ChatCompletions, "chat"));
+        assert!(!access.can_use(&AiRouterModelType::ChatCompletions, "other"));
+        assert!(!access.can_use(&AiRouterModelType::Embeddings, "embed"));
+        assert_eq!(access.backend_api_key("openai"), Some("team_a_openai_key"));
+        assert_eq!(access.backend_api_key("triton"), None);
+
+        let access = find_api_key(&config, "team_b_key", 0).expect("team_b key rejected");
+        assert!(access.can_use(&AiRouterModelType::Embeddings, "embed"));
+        assert!(!access.can_use(&AiRouterModelType::ChatCompletions, "chat"));
+
+        let access = find_api_key(&config, "team_c_key", 0).expect("team_c key rejected");
+        assert!(access.can_use(&AiRouterModelType::ChatCompletions, "gpt-4*"));
+        assert!(access.can_use(&AiRouterModelType::ChatCompletions, "gpt-4o"));
+        assert!(!access.can_use(&AiRouterModelType::ChatCompletions, "chat"));
+
+        assert!(find_api_key(&config, "team_b_key", 1_700_000_000).is_err());
+        assert!(find_api_key(&config, "unknown_key", 0).is_err());
+
+        let debug = format!("{:?}", config.api_keys["team_a"]);
+        assert!(!debug.contains("team_a_key"));
+        assert!(!debug.contains("team_a_openai_key"));
+    }
+
+    #[test]
+    fn test_redact_api_key() {
+        assert_eq!(redact_api_key("short"), "****");
+        assert_eq!(redact_api_key("sk-0123456789abcdef"), "sk-0****cdef");
+    }
+}
@@ -1,37 +1,56 @@
+pub(crate) mod health;
 pub mod openai;
+pub(crate) mod selector;
 pub mod triton;
 
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 use openai_dive::v1::api::Client as OpenAIClient;
-use tonic::transport::Channel;
+use tonic::transport::{Channel, Endpoint};
 
+use crate::auth::ApiKeyAccess;
+use crate::backend::health::BackendHealth;
 use crate::backend::triton::grpc_inference_service_client::GrpcInferenceServiceClient;
 use crate::config::{AiRouterBackend, AiRouterBackendType, AiRouterConfigFile};
+use crate::errors::AiRouterError;
 use crate::state::BackendTypes;
 
 type BackendClient = BackendTypes<OpenAIClient, GrpcInferenceServiceClient<Channel>>;
-pub type Backends = HashMap<String, Backend>;
+pub type Backends = HashMap<String, Arc<Backend>>;
 
 #[derive(Debug)]
 pub struct Backend {
     pub client: BackendClient,
+    pub health: BackendHealth,
+    pub name: String,
+    pub timeout: Option<Duration>,
+    outstanding: AtomicUsize,
 }
 
 impl Backend {
+    /// `OpenAI` backends without API key use the API key of the client request, see
+    /// `Backend::openai_client`.
+    ///
+    /// Triton backends are connected lazily on the first request, so unreachable backends do not
+    /// prevent the router from starting. The connection is re-established automatically when the
+    /// backend becomes reachable again. Must be called from within a Tokio runtime.
+    ///
     /// # Panics
-    /// - when trying to initialize an `OpenAI` backend without API key
-    /// - when unable to connect to a Trinton backend
-    pub async fn new(name: &String, backend: &AiRouterBackend) -> Self {
+    /// - when the `base_url` of a Triton backend is not a valid URI
+    pub fn new(name: &String, backend: &AiRouterBackend) -> Self {
         let client: BackendClient = match backend.backend_type {
             AiRouterBackendType::OpenAI => {
                 println!("initializing OpenAI backend {name}");
+                if backend.api_key.is_none() {
+                    println!(
+                        "OpenAI backend {name} has no API key, passing through client API keys"
+                    );
+                }
                 BackendClient::OpenAI(OpenAIClient {
-                    api_key: backend
-                        .api_key
-                        .as_ref()
-                        .unwrap_or_else(|| panic!("OpenAI backend {name} is missing API key"))
-                        .clone(),
+                    api_key: backend.api_key.clone().unwrap_or_default(),
                     base_url: backend.base_url.clone(),
                     headers: None,
                     http_client: reqwest::Client::new(),
@@ -41,31 +60,71 @@ impl Backend {
             }
             AiRouterBackendType::Triton => {
                 println!("initializing Triton backend {name}");
-                BackendClient::Triton(
-                    GrpcInferenceServiceClient::connect(backend.base_url.clone())
-                        .await
-                        .unwrap_or_else(|e| {
-                            panic!(
-                                "failed to connect to Triton backend {name} ({}): {e:?}",
-                                backend.base_url
-                            )
-                        }),
-                )
+                let channel = Endpoint::from_shared(backend.base_url.clone())
+                    .unwrap_or_else(|e| {
+                        panic!(
+                            "invalid base_url for Triton backend {name} ({}): {e:?}",
+                            backend.base_url
+                        )
+                    })
+                    .connect_lazy();
+                BackendClient::Triton(GrpcInferenceServiceClient::new(channel))
             }
         };
 
-        Self { client }
+        Self {
+            client,
+            health: BackendHealth::new(name, backend),
+            name: name.clone(),
+            timeout: backend.timeout.map(Duration::from_secs),
+            outstanding: AtomicUsize::new(0),
+        }
+    }
+
+    /// Number of requests currently in flight to this backend
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.load(Ordering::Relaxed)
     }
 
-    pub async fn init(config: &AiRouterConfigFile) -> HashMap<String, Self> {
+    /// Get the client to use for a request to an `OpenAI` backend
+    ///
+    /// When the backend has no API key configured, the client is parameterized with the API key
+    /// mapped to this backend for the client API key, or the API key sent by the client.
+    ///
+    /// # Errors
+    /// `AiRouterError::InvalidApiKey` when the backend has no API key and the client request has
+    /// none to pass through
+    pub(crate) fn openai_client(
+        &self,
+        client: &OpenAIClient,
+        api_key: &ApiKeyAccess,
+    ) -> Result<OpenAIClient, AiRouterError<String>> {
+        let mut client = client.clone();
+
+        if client.api_key.is_empty() {
+            let Some(backend_api_key) = api_key.backend_api_key(&self.name) else {
+                return Err(AiRouterError::InvalidApiKey(format!(
+                    "Backend {} requires an API key but none was provided for it.",
+                    self.name
+                )));
+            };
+            client.api_key = String::from(backend_api_key);
+        }
+
+        Ok(client)
+    }
+
+    pub fn init(config: &AiRouterConfigFile) -> Backends {
         let mut map: Backends = HashMap::new();
 
         for (name, backend) in &config.backends {
-            map.insert(name.clone(), Self::new(name, backend).await);
+            let initialized = Arc::new(Self::new(name, backend));
 
             if backend.default.unwrap_or(false) {
-                map.insert(String::from("default"), Self::new(name, backend).await);
+                map.insert(String::from("default"), initialized.clone());
             }
+
+            map.insert(name.clone(), initialized);
         }
 
         map
@@ -74,7 +133,10 @@ impl Backend {
 
 #[cfg(test)]
 mod tests {
+    use crate::backend::triton::ServerReadyRequest;
     use crate::config::AiRouterConfigFile;
+    use crate::errors::{transform_triton_status, AiRouterError};
+    use crate::state::BackendTypes;
 
     use super::Backend;
 
@@ -84,6 +146,29 @@ mod tests {
             AiRouterConfigFile::parse(String::from("tests/ai-router.toml.default_backend"))
                 .expect("failed to load test config file");
 
-        assert!(Backend::init(&config_file).await.contains_key("default"));
+        assert!(Backend::init(&config_file).contains_key("default"));
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_backend() {
+        let config_file =
+            AiRouterConfigFile::parse(String::from("tests/ai-router.toml.unreachable_backend"))
+                .expect("failed to load test config file");
+
+        let backends = Backend::init(&config_file);
+        let BackendTypes::Triton(client) = &backends["triton"].client else {
+            panic!("expected Triton backend");
+        };
+
+        let status = client
+            .clone()
+            .server_ready(ServerReadyRequest {})
+            .await
+            .expect_err("unreachable backend should fail");
+
+        assert!(matches!(
+            transform_triton_status(&status),
+            AiRouterError::ServiceUnavailable(_)
+        ));
     }
 }
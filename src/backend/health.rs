@@ -0,0 +1,329 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tonic::transport::Channel;
+
+use crate::backend::triton::grpc_inference_service_client::GrpcInferenceServiceClient;
+use crate::backend::triton::{ModelReadyRequest, ServerReadyRequest};
+use crate::backend::{Backend, Backends};
+use crate::config::{AiRouterBackend, AiRouterConfigFile};
+use crate::models::is_pattern;
+use crate::state::BackendTypes;
+
+const DEFAULT_CIRCUIT_BREAKER_THRESHOLD: u32 = 5;
+const DEFAULT_CIRCUIT_BREAKER_TIMEOUT: u64 = 30;
+const DEFAULT_HEALTH_CHECK_INTERVAL: u64 = 10;
+
+/// Health of a backend, from active health checks and a circuit breaker on failed requests
+#[derive(Debug)]
+pub struct BackendHealth {
+    name: String,
+    healthy: AtomicBool,
+    consecutive_failures: AtomicU32,
+    circuit_open_until: Mutex<Option<Instant>>,
+    circuit_breaker_threshold: u32,
+    circuit_breaker_timeout: Duration,
+}
+
+impl BackendHealth {
+    pub fn new(name: &str, backend: &AiRouterBackend) -> Self {
+        let health = Self {
+            name: String::from(name),
+            healthy: AtomicBool::new(true),
+            consecutive_failures: AtomicU32::new(0),
+            circuit_open_until: Mutex::new(None),
+            circuit_breaker_threshold: backend
+                .circuit_breaker_threshold
+                .unwrap_or(DEFAULT_CIRCUIT_BREAKER_THRESHOLD),
+            circuit_breaker_timeout: Duration::from_secs(
+                backend
+                    .circuit_breaker_timeout
+                    .unwrap_or(DEFAULT_CIRCUIT_BREAKER_TIMEOUT),
+            ),
+        };
+
+        health.set_healthy(true);
+        health.update_circuit_gauge(false);
+
+        health
+    }
+
+    /// Check if requests can be sent to the backend
+    ///
+    /// Once `circuit_breaker_timeout` has passed, requests are sent to the backend again. The
+    /// circuit breaker closes on the first successful request, and opens again on a failure.
+    pub fn is_available(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed) && !self.is_circuit_open()
+    }
+
+    /// Check if the circuit breaker is open, and update its gauge once `circuit_breaker_timeout`
+    /// has passed
+    pub fn is_circuit_open(&self) -> bool {
+        let open = self
+            .circuit_open_until
+            .lock()
+            .is_ok_and(|until| until.is_some_and(|u| u > Instant::now()));
+        self.update_circuit_gauge(open);
+
+        open
+    }
+
+    pub fn record_failure(&self) {
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+
+        if failures >= self.circuit_breaker_threshold {
+            if let Ok(mut until) = self.circuit_open_until.lock() {
+                tracing::warn!(
+                    "opening circuit breaker for backend {} after {failures} consecutive failures",
+                    self.name
+                );
+                *until = Some(Instant::now() + self.circuit_breaker_timeout);
+            }
+            self.update_circuit_gauge(true);
+        }
+    }
+
+    pub fn record_success(&self) {
+        if self.consecutive_failures.swap(0, Ordering::Relaxed) == 0 {
+            return;
+        }
+
+        if let Ok(mut until) = self.circuit_open_until.lock() {
+            if until.take().is_some() {
+                tracing::info!("closing circuit breaker for backend {}", self.name);
+            }
+        }
+        self.update_circuit_gauge(false);
+    }
+
+    fn set_healthy(&self, healthy: bool) {
+        if self.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+            if healthy {
+                tracing::info!("backend {} is healthy", self.name);
+            } else {
+                tracing::warn!("backend {} is unhealthy", self.name);
+            }
+        }
+
+        metrics::gauge!("ai_router_backend_healthy", "backend" => self.name.clone())
+            .set(f64::from(u8::from(healthy)));
+    }
+
+    fn update_circuit_gauge(&self, open: bool) {
+        metrics::gauge!("ai_router_backend_circuit_open", "backend" => self.name.clone())
+            .set(f64::from(u8::from(open)));
+    }
+}
+
+/// Spawn a task per backend that periodically checks if the backend is ready
+///
+/// Triton backends are checked with `ServerReady` and `ModelReady` for all models served by the
+/// backend, `OpenAI` backends with `GET /models`.
+pub fn spawn_health_checks(backends: &Backends, config: &AiRouterConfigFile) {
+    for (name, backend) in backends {
+        // skip the alias of the default backend
+        if *name != backend.name {
+            continue;
+        }
+
+        let Some(backend_config) = config.backends.get(name) else {
+            continue;
+        };
+
+        let interval = backend_config
+            .health_check_interval
+            .unwrap_or(DEFAULT_HEALTH_CHECK_INTERVAL);
+        if interval == 0 {
+            continue;
+        }
+
+        let backend = backend.clone();
+        let base_url = backend_config.base_url.clone();
+        let models = get_backend_models(name, config);
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(interval));
+            loop {
+                interval.tick().await;
+                let healthy = check(&backend, &base_url, &models).await;
+                backend.health.set_healthy(healthy);
+                // keeps the gauge up to date without requests to the backend
+                backend.health.is_circuit_open();
+            }
+        });
+    }
+}
+
+async fn check(backend: &Arc<Backend>, base_url: &str, models: &[String]) -> bool {
+    match &backend.client {
+        BackendTypes::OpenAI(c) => {
+            let mut request = c.http_client.get(format!("{base_url}/models"));
+            if !c.api_key.is_empty() {
+                request = request.bearer_auth(&c.api_key);
+            }
+
+            match request.send().await {
+                // backends passing through client API keys are expected to reject our request
+                Ok(response) if c.api_key.is_empty() => !response.status().is_server_error(),
+                Ok(response) => response.status().is_success(),
+                Err(e) => {
+                    tracing::debug!("health check for backend {} failed: {e}", backend.name);
+                    false
+                }
+            }
+        }
+        BackendTypes::Triton(c) => check_triton(&backend.name, c, models).await,
+    }
+}
+
+async fn check_triton(
+    name: &str,
+    client: &GrpcInferenceServiceClient<Channel>,
+    models: &[String],
+) -> bool {
+    let mut client = client.clone();
+
+    match client.server_ready(ServerReadyRequest {}).await {
+        Ok(response) if response.get_ref().ready => {}
+        Ok(_) => return false,
+        Err(e) => {
+            tracing::debug!("health check for backend {name} failed: {e}");
+            return false;
+        }
+    }
+
+    for model in models {
+        let request = ModelReadyRequest {
+            name: model.clone(),
+            version: String::new(),
+        };
+        match client.model_ready(request).await {
+            Ok(response) if response.get_ref().ready => {}
+            Ok(_) => {
+                tracing::debug!("model {model} on backend {name} is not ready");
+                return false;
+            }
+            Err(e) => {
+                tracing::debug!("health check for model {model} on backend {name} failed: {e}");
+                return false;
+            }
+        }
+    }
+
+    true
+}
+
+/// Names of the models a backend serves, as sent to the backend
+fn get_backend_models(name: &str, config: &AiRouterConfigFile) -> Vec<String> {
+    let is_default = config
+        .backends
+        .get(name)
+        .is_some_and(|b| b.default.unwrap_or(false));
+    let mut models: Vec<String> = Vec::new();
+
+    for models_of_type in config.models.values() {
+        for (model_name, model) in models_of_type {
+            let backend_model = model
+                .backend_model
+                .clone()
+                .unwrap_or_else(|| model_name.clone());
+
+            let serves = model
+                .backend
+                .as_ref()
+                .map_or(is_default, |b| b.iter().any(|b| b == name));
+            if serves && is_literal_model(&backend_model) && !models.contains(&backend_model) {
+                models.push(backend_model.clone());
+            }
+
+            for fallback in model.fallback.iter().flatten() {
+                if fallback.backend != name {
+                    continue;
+                }
+                let fallback_model = fallback
+                    .backend_model
+                    .clone()
+                    .unwrap_or_else(|| backend_model.clone());
+                if is_literal_model(&fallback_model) && !models.contains(&fallback_model) {
+                    models.push(fallback_model);
+                }
+            }
+        }
+    }
+
+    models.sort();
+    models
+}
+
+/// Check if a backend model name is a literal name rather than a glob pattern, or a template
+/// whose `$1`, `$2`, ... placeholders depend on the model name in the client request
+fn is_literal_model(backend_model: &str) -> bool {
+    let has_placeholder = backend_model
+        .split('$')
+        .skip(1)
+        .any(|part| part.starts_with(|c: char| c.is_ascii_digit()));
+    !is_pattern(backend_model) && !has_placeholder
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let backend: AiRouterBackend = toml::from_str(
+            r#"
+                type = "triton"
+                base_url = "http://127.0.0.1:8001"
+                circuit_breaker_threshold = 2
+            "#,
+        )
+        .expect("failed to parse backend config");
+        let health = BackendHealth::new("triton", &backend);
+
+        assert!(health.is_available());
+
+        health.record_failure();
+        assert!(health.is_available());
+
+        health.record_failure();
+        assert!(health.is_circuit_open());
+        assert!(!health.is_available());
+
+        health.record_success();
+        assert!(!health.is_circuit_open());
+        assert!(health.is_available());
+
+        health.set_healthy(false);
+        assert!(!health.is_available());
+    }
+
+    #[test]
+    fn test_get_backend_models() {
+        let config = AiRouterConfigFile::parse(String::from("tests/ai-router.toml.load_balancing"))
+            .expect("failed to load test config file");
+
+        assert_eq!(
+            get_backend_models("backend_1", &config),
+            [
+                "default_backend",
+                "least_outstanding",
+                "primary",
+                "round_robin"
+            ]
+        );
+        assert_eq!(
+            get_backend_models("backend_2", &config),
+            ["least_outstanding", "round_robin", "secondary"]
+        );
+    }
+
+    #[test]
+    fn test_get_backend_models_patterns() {
+        let config = AiRouterConfigFile::parse(String::from("tests/ai-router.toml.model_patterns"))
+            .expect("failed to load test config file");
+
+        assert_eq!(get_backend_models("openai", &config), ["gpt-4o-mini"]);
+    }
+}
@@ -1,3 +1,115 @@
+//! Requests not supported by `openai_dive` are sent with the HTTP client of the `OpenAI` backend
+//! client, using the helpers in this module.
+use async_stream::try_stream;
+use bytes::Bytes;
+use openai_dive::v1::api::Client;
+use reqwest::RequestBuilder;
+use tonic::codegen::tokio_stream::{Stream, StreamExt};
+
+use crate::errors::{transform_openai_error_body, AiRouterError};
+
 pub mod audio;
 pub mod chat;
+pub mod completions;
 pub mod embeddings;
+
+/// Build a POST request to `path` of the backend, with the headers of the backend client
+fn post(client: &Client, path: &str) -> RequestBuilder {
+    let mut builder = client
+        .http_client
+        .post(format!("{}/{path}", client.base_url))
+        .bearer_auth(&client.api_key);
+
+    if let Some(organization) = &client.organization {
+        builder = builder.header("OpenAI-Organization", organization);
+    }
+    if let Some(project) = &client.project {
+        builder = builder.header("OpenAI-Project", project);
+    }
+    for (name, value) in client.headers.iter().flatten() {
+        builder = builder.header(name, value);
+    }
+
+    builder
+}
+
+async fn send(builder: RequestBuilder) -> Result<reqwest::Response, AiRouterError<String>> {
+    let response = builder.send().await.map_err(|e| {
+        if e.is_connect() {
+            AiRouterError::ServiceUnavailable(format!("OpenAI backend unavailable: {e}"))
+        } else if e.is_timeout() {
+            AiRouterError::GatewayTimeout(format!("OpenAI backend timed out: {e}"))
+        } else {
+            AiRouterError::from(e)
+        }
+    })?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        tracing::debug!("backend returned {status}: {body}");
+        return Err(transform_openai_error_body(&body, status));
+    }
+
+    Ok(response)
+}
+
+/// Get the data of each server-sent event in a response body
+fn sse_data_stream<S>(body: S) -> impl Stream<Item = anyhow::Result<String>>
+where
+    S: Stream<Item = reqwest::Result<Bytes>>,
+{
+    let mut body = Box::pin(body);
+
+    try_stream! {
+        let mut buffer: Vec<u8> = Vec::new();
+
+        while let Some(chunk) = body.next().await {
+            buffer.extend(chunk?.iter().filter(|b| **b != b'\r'));
+
+            while let Some(end) = buffer.windows(2).position(|w| w == b"\n\n") {
+                let event: Vec<u8> = buffer.drain(..end + 2).collect();
+                if let Some(data) = get_sse_data(&String::from_utf8_lossy(&event)) {
+                    yield data;
+                }
+            }
+        }
+    }
+}
+
+/// Get the data of a server-sent event, joining multiple data lines
+fn get_sse_data(event: &str) -> Option<String> {
+    let data: Vec<&str> = event
+        .lines()
+        .filter_map(|line| line.strip_prefix("data:"))
+        .map(|data| data.strip_prefix(' ').unwrap_or(data))
+        .collect();
+
+    if data.is_empty() {
+        return None;
+    }
+
+    Some(data.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::get_sse_data;
+
+    #[test]
+    fn test_get_sse_data() {
+        assert_eq!(
+            get_sse_data("data: {\"id\":\"cmpl-1\"}\n\n"),
+            Some(String::from("{\"id\":\"cmpl-1\"}"))
+        );
+        assert_eq!(
+            get_sse_data("event: message\ndata: [DONE]\n\n"),
+            Some(String::from("[DONE]"))
+        );
+        assert_eq!(
+            get_sse_data("data:a\ndata:b\n\n"),
+            Some(String::from("a\nb"))
+        );
+        assert_eq!(get_sse_data(": keep-alive\n\n"), None);
+    }
+}
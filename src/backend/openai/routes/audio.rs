@@ -1,17 +1,33 @@
+use async_stream::try_stream;
 use axum::{
-    response::{IntoResponse, Response},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
     Json,
 };
+use bytes::Bytes;
 use openai_dive::v1::{
     api::Client,
-    resources::audio::{
-        AudioOutputFormat, AudioSpeechParameters, AudioSpeechResponseFormat,
-        AudioTranscriptionParameters,
+    resources::{
+        audio::{
+            AudioOutputFormat, AudioSpeechParameters, AudioSpeechResponseFormat,
+            AudioTranscriptionParameters, AudioTranslationParameters,
+        },
+        shared::{FileUpload, FileUploadBytes},
     },
 };
+use reqwest::multipart::{Form, Part};
+use serde_json::Value;
+use tonic::codegen::tokio_stream::{Stream, StreamExt};
 
+use super::{post, send, sse_data_stream};
+use crate::audio::transcription::{stream_response, Transcription, TranscriptionTask};
+use crate::audio::{encode_wav, f32_to_i16, DecodedAudio};
 use crate::errors::{transform_openai_dive_apierror, AiRouterError};
 
+const TRANSCRIPT_DONE_EVENT: &str = "transcript.text.done";
+
 pub async fn speech(
     client: &Client,
     parameters: AudioSpeechParameters,
@@ -42,6 +58,10 @@ pub async fn transcriptions(
     client: &Client,
     parameters: AudioTranscriptionParameters,
 ) -> Result<Response, AiRouterError<String>> {
+    if parameters.stream == Some(true) {
+        return transcriptions_stream(client, parameters).await;
+    }
+
     let response_format = parameters.response_format.clone();
 
     let response = client
@@ -50,12 +70,160 @@ pub async fn transcriptions(
         .await
         .map_err(|e| transform_openai_dive_apierror(&e))?;
 
+    Ok(into_response(response, response_format))
+}
+
+/// `OpenAI` does not stream translations, the events of a streaming response are sent once the
+/// translation is complete
+pub async fn translations(
+    client: &Client,
+    parameters: AudioTranscriptionParameters,
+) -> Result<Response, AiRouterError<String>> {
+    if parameters.stream == Some(true) {
+        let translation = create(client, parameters, TranscriptionTask::Translate).await?;
+        let events = translation.into_stream_events().into_iter().map(Ok);
+        return stream_response(futures::stream::iter(events)).await;
+    }
+
+    let response_format = parameters.response_format.clone();
+
+    let response = client
+        .audio()
+        .create_translation(build_translation_parameters(parameters))
+        .await
+        .map_err(|e| transform_openai_dive_apierror(&e))?;
+
+    Ok(into_response(response, response_format))
+}
+
+/// Transcribe or translate audio, returning the `verbose_json` transcription for
+/// `transcribe_chunked`
+pub async fn transcribe(
+    client: Client,
+    mut parameters: AudioTranscriptionParameters,
+    audio: DecodedAudio,
+    task: TranscriptionTask,
+) -> Result<Transcription, AiRouterError<String>> {
+    let wav = encode_wav(&f32_to_i16(&audio.samples), audio.sample_rate)?;
+
+    parameters.file = FileUpload::Bytes(FileUploadBytes {
+        bytes: Bytes::from(wav),
+        filename: String::from("chunk.wav"),
+    });
+
+    create(&client, parameters, task).await
+}
+
+/// Transcribe or translate the uploaded file, returning the `verbose_json` transcription
+async fn create(
+    client: &Client,
+    mut parameters: AudioTranscriptionParameters,
+    task: TranscriptionTask,
+) -> Result<Transcription, AiRouterError<String>> {
+    parameters.response_format = Some(AudioOutputFormat::VerboseJson);
+    parameters.stream = None;
+    parameters.timestamp_granularities = None;
+
+    let response = match task {
+        TranscriptionTask::Transcribe => client.audio().create_transcription(parameters).await,
+        TranscriptionTask::Translate => {
+            client
+                .audio()
+                .create_translation(build_translation_parameters(parameters))
+                .await
+        }
+    }
+    .map_err(|e| transform_openai_dive_apierror(&e))?;
+
+    Ok(serde_json::from_str(&response)?)
+}
+
+/// Proxy a streaming transcription, `openai_dive` does not support streaming transcriptions
+async fn transcriptions_stream(
+    client: &Client,
+    parameters: AudioTranscriptionParameters,
+) -> Result<Response, AiRouterError<String>> {
+    let FileUpload::Bytes(file) = parameters.file else {
+        return Err(AiRouterError::BadRequestError(String::from(
+            "no audio file uploaded",
+        )));
+    };
+
+    let mut form = Form::new()
+        .part(
+            "file",
+            Part::bytes(file.bytes.to_vec()).file_name(file.filename),
+        )
+        .text("model", parameters.model)
+        .text("stream", "true");
+    if let Some(language) = parameters.language {
+        form = form.text("language", language);
+    }
+    if let Some(prompt) = parameters.prompt {
+        form = form.text("prompt", prompt);
+    }
+    if let Some(response_format) = parameters.response_format {
+        form = form.text("response_format", serde_plain::to_string(&response_format)?);
+    }
+    if let Some(temperature) = parameters.temperature {
+        form = form.text("temperature", temperature.to_string());
+    }
+
+    let body = send(post(client, "audio/transcriptions").multipart(form))
+        .await?
+        .bytes_stream();
+
+    Ok(Sse::new(proxy_events(sse_data_stream(body)))
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+/// Forward the events of a streaming transcription until `transcript.text.done`
+fn proxy_events<S>(events: S) -> impl Stream<Item = anyhow::Result<Event>>
+where
+    S: Stream<Item = anyhow::Result<String>>,
+{
+    let mut events = Box::pin(events);
+
+    try_stream! {
+        while let Some(data) = events.next().await {
+            let data = data?;
+            tracing::debug!("{data}");
+
+            if data == "[DONE]" {
+                yield Event::default().data("[DONE]");
+                return;
+            }
+
+            let event: Value = serde_json::from_str(&data)?;
+            yield Event::default().json_data(&event)?;
+            if event["type"] == TRANSCRIPT_DONE_EVENT {
+                return;
+            }
+        }
+    }
+}
+
+/// Translations take the same multipart form as transcriptions, without `language`
+fn build_translation_parameters(
+    parameters: AudioTranscriptionParameters,
+) -> AudioTranslationParameters {
+    AudioTranslationParameters {
+        file: parameters.file,
+        model: parameters.model,
+        prompt: parameters.prompt,
+        response_format: parameters.response_format,
+        temperature: parameters.temperature,
+    }
+}
+
+fn into_response(response: String, response_format: Option<AudioOutputFormat>) -> Response {
     match response_format {
         None | Some(AudioOutputFormat::Json | AudioOutputFormat::VerboseJson) => {
-            Ok(Json(response).into_response())
+            Json(response).into_response()
         }
         Some(AudioOutputFormat::Srt | AudioOutputFormat::Text | AudioOutputFormat::Vtt) => {
-            Ok(response.into_response())
+            response.into_response()
         }
     }
 }
@@ -0,0 +1,99 @@
+//! <https://platform.openai.com/docs/api-reference/completions/create>
+//!
+//! `openai_dive` does not support the legacy completions API.
+use async_stream::try_stream;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use openai_dive::v1::api::Client;
+use reqwest::RequestBuilder;
+use serde_json::Value;
+use tonic::codegen::tokio_stream::{Stream, StreamExt};
+use tracing::instrument;
+
+use super::{post, send, sse_data_stream};
+use crate::backend::triton::routes::completions::CompletionCreateParams;
+use crate::errors::AiRouterError;
+use crate::request::AiRouterRequestData;
+
+#[instrument(skip(client, request))]
+pub async fn wrap_completion(
+    client: Client,
+    request: Json<CompletionCreateParams>,
+    request_data: &AiRouterRequestData,
+) -> Response {
+    if request.stream {
+        completion_stream(client, request, request_data)
+            .await
+            .into_response()
+    } else {
+        completion(client, request, request_data)
+            .await
+            .into_response()
+    }
+}
+
+#[instrument(skip(client, request))]
+async fn completion(
+    client: Client,
+    Json(request): Json<CompletionCreateParams>,
+    request_data: &AiRouterRequestData,
+) -> Result<Json<Value>, AiRouterError<String>> {
+    let response_model = request_data
+        .original_model
+        .clone()
+        .unwrap_or_else(|| request.model.clone());
+
+    let response = send(build_request(&client, &request)?).await?;
+
+    let mut response: Value = serde_json::from_slice(&response.bytes().await?)?;
+    response["model"] = Value::String(response_model);
+
+    Ok(Json(response))
+}
+
+#[instrument(skip(client, request))]
+async fn completion_stream(
+    client: Client,
+    Json(request): Json<CompletionCreateParams>,
+    request_data: &AiRouterRequestData,
+) -> Result<Sse<impl Stream<Item = anyhow::Result<Event>>>, AiRouterError<String>> {
+    let response_model = request_data
+        .original_model
+        .clone()
+        .unwrap_or_else(|| request.model.clone());
+
+    let body = send(build_request(&client, &request)?)
+        .await?
+        .bytes_stream();
+    let mut events = Box::pin(sse_data_stream(body));
+
+    let response_stream = try_stream! {
+        while let Some(data) = events.next().await {
+            let data = data?;
+
+            if data == "[DONE]" {
+                // OpenAI stream response terminated by a data: [DONE] message.
+                yield Event::default().data("[DONE]");
+                return;
+            }
+
+            let mut response: Value = serde_json::from_str(&data)?;
+            tracing::debug!("{response:?}");
+            response["model"] = Value::String(response_model.clone());
+            yield Event::default().json_data(response)?;
+        }
+    };
+
+    Ok(Sse::new(response_stream).keep_alive(KeepAlive::default()))
+}
+
+fn build_request(
+    client: &Client,
+    request: &CompletionCreateParams,
+) -> Result<RequestBuilder, AiRouterError<String>> {
+    Ok(post(client, "completions")
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(request)?))
+}
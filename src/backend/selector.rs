@@ -0,0 +1,471 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use axum::body::Body;
+use axum::response::{IntoResponse, Response};
+use rand::Rng;
+use tonic::codegen::tokio_stream::StreamExt;
+
+use crate::backend::{Backend, Backends};
+use crate::config::{AiRouterLoadBalancing, AiRouterModel, AiRouterModelType, AiRouterModels};
+use crate::errors::AiRouterError;
+
+const DEFAULT_BACKEND: &str = "default";
+
+/// Select the backend for a request when a model is served by multiple backends
+#[derive(Debug)]
+pub struct BackendSelector {
+    round_robin: HashMap<(AiRouterModelType, String), AtomicUsize>,
+}
+
+impl BackendSelector {
+    pub fn new(models: &AiRouterModels) -> Self {
+        let mut round_robin = HashMap::new();
+
+        for (model_type, models) in models {
+            for model_name in models.keys() {
+                round_robin.insert(
+                    (model_type.clone(), model_name.clone()),
+                    AtomicUsize::new(0),
+                );
+            }
+        }
+
+        Self { round_robin }
+    }
+
+    /// Backends with an open circuit breaker or failing health checks are skipped.
+    ///
+    /// # Errors
+    /// - `AiRouterError::InternalServerError` when none of the backends configured for the model exist
+    /// - `AiRouterError::ServiceUnavailable` when none of the backends configured for the model are available
+    pub fn select(
+        &self,
+        backends: &Backends,
+        model_type: &AiRouterModelType,
+        model_name: &str,
+        model: &AiRouterModel,
+    ) -> Result<Arc<Backend>, AiRouterError<String>> {
+        let candidates: Vec<&Arc<Backend>> = match &model.backend {
+            Some(names) => names.iter().filter_map(|n| backends.get(n)).collect(),
+            None => backends.get(DEFAULT_BACKEND).into_iter().collect(),
+        };
+
+        if candidates.is_empty() {
+            let names = model
+                .backend
+                .as_ref()
+                .map_or_else(|| String::from(DEFAULT_BACKEND), |b| b.join(", "));
+            return Err(AiRouterError::InternalServerError::<String>(format!(
+                "backend {names} not found"
+            )));
+        }
+
+        let candidates: Vec<&Arc<Backend>> = candidates
+            .into_iter()
+            .filter(|b| b.health.is_available())
+            .collect();
+
+        if candidates.is_empty() {
+            return Err(AiRouterError::ServiceUnavailable::<String>(format!(
+                "no backend available for model {model_name}"
+            )));
+        }
+
+        if candidates.len() == 1 {
+            return Ok(candidates[0].clone());
+        }
+
+        let idx = match model.load_balancing.clone().unwrap_or_default() {
+            AiRouterLoadBalancing::LeastOutstanding => {
+                // start at the round-robin position so ties are spread across backends
+                let offset = self.next(model_type, model_name);
+                least_outstanding(&candidates, offset)
+            }
+            AiRouterLoadBalancing::Random => rand::thread_rng().gen_range(0..candidates.len()),
+            AiRouterLoadBalancing::RoundRobin => {
+                self.next(model_type, model_name) % candidates.len()
+            }
+        };
+
+        tracing::debug!(
+            "selected backend {} for model {model_name}",
+            candidates[idx].name
+        );
+
+        Ok(candidates[idx].clone())
+    }
+
+    /// Select the backend for a request followed by the fallback backends of the model
+    ///
+    /// # Errors
+    /// - `AiRouterError::InternalServerError` when none of the backends configured for the model exist
+    /// - `AiRouterError::ServiceUnavailable` when neither the backends configured for the model nor
+    ///   its fallback backends are available
+    pub fn select_with_fallback(
+        &self,
+        backends: &Backends,
+        model_type: &AiRouterModelType,
+        model_name: &str,
+        model: &AiRouterModel,
+    ) -> Result<Vec<SelectedBackend>, AiRouterError<String>> {
+        let mut selected: Vec<SelectedBackend> = Vec::new();
+
+        match self.select(backends, model_type, model_name, model) {
+            Ok(backend) => selected.push(SelectedBackend {
+                backend,
+                backend_model: model.backend_model.clone(),
+            }),
+            Err(AiRouterError::ServiceUnavailable(e)) if model.fallback.is_some() => {
+                tracing::warn!("{e}, trying fallback backends");
+            }
+            Err(e) => return Err(e),
+        }
+
+        for fallback in model.fallback.iter().flatten() {
+            let Some(backend) = backends.get(&fallback.backend) else {
+                tracing::warn!(
+                    "fallback backend {} for model {model_name} not found",
+                    fallback.backend
+                );
+                continue;
+            };
+            if !backend.health.is_available() {
+                tracing::debug!("skipping unavailable fallback backend {}", backend.name);
+                continue;
+            }
+            selected.push(SelectedBackend {
+                backend: backend.clone(),
+                backend_model: fallback
+                    .backend_model
+                    .clone()
+                    .or_else(|| model.backend_model.clone()),
+            });
+        }
+
+        if selected.is_empty() {
+            return Err(AiRouterError::ServiceUnavailable::<String>(format!(
+                "no backend available for model {model_name}"
+            )));
+        }
+
+        Ok(selected)
+    }
+
+    fn next(&self, model_type: &AiRouterModelType, model_name: &str) -> usize {
+        self.round_robin
+            .get(&(model_type.clone(), String::from(model_name)))
+            .map_or(0, |c| c.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+fn least_outstanding(candidates: &[&Arc<Backend>], offset: usize) -> usize {
+    (0..candidates.len())
+        .map(|i| (i + offset) % candidates.len())
+        .min_by_key(|&i| candidates[i].outstanding())
+        .unwrap_or(0)
+}
+
+/// Backend to send a request to, and the model name to use in the request
+#[derive(Debug)]
+pub struct SelectedBackend {
+    pub backend: Arc<Backend>,
+    pub backend_model: Option<String>,
+}
+
+/// Send a request to the first backend, and to the next one when it fails
+///
+/// A backend failed when it cannot be reached, returns a server error or does not respond within
+/// its `timeout`, see `AiRouterError::is_backend_failure`. Client errors, e.g. an invalid request
+/// or a rate limit of the backend, and errors of the router, e.g. a missing tokenizer, are
+/// returned to the client without trying the fallback backends or counting as backend failure.
+/// Backend routes only return streaming responses after receiving the first chunk, so streams
+/// that fail before any bytes are sent to the client are retried as well.
+pub async fn send_with_fallback<F, Fut>(selected: Vec<SelectedBackend>, mut send: F) -> Response
+where
+    F: FnMut(SelectedBackend) -> Fut,
+    Fut: Future<Output = Result<Response, AiRouterError<String>>>,
+{
+    let num_backends = selected.len();
+
+    for (i, selected) in selected.into_iter().enumerate() {
+        let backend = selected.backend.clone();
+        let outstanding = OutstandingRequest::new(&backend);
+
+        let result = match backend.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, send(selected))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(AiRouterError::GatewayTimeout(format!(
+                        "backend {} did not respond within {timeout:?}",
+                        backend.name
+                    )))
+                }),
+            None => send(selected).await,
+        };
+        let failed = match &result {
+            Ok(response) => response.status().is_server_error(),
+            Err(e) => e.is_backend_failure(),
+        };
+        let response = result.unwrap_or_else(IntoResponse::into_response);
+
+        if failed {
+            backend.health.record_failure();
+        } else {
+            backend.health.record_success();
+        }
+
+        if i + 1 < num_backends && failed {
+            tracing::warn!(
+                "backend {} failed with status {}, trying next fallback backend",
+                backend.name,
+                response.status()
+            );
+            continue;
+        }
+
+        return outstanding.attach(response);
+    }
+
+    AiRouterError::<String>::InternalServerError(String::from("no backend to send request to"))
+        .into_response()
+}
+
+/// Tracks a request in flight to a backend for `AiRouterLoadBalancing::LeastOutstanding`
+pub struct OutstandingRequest {
+    backend: Arc<Backend>,
+}
+
+impl OutstandingRequest {
+    pub fn new(backend: &Arc<Backend>) -> Self {
+        backend.outstanding.fetch_add(1, Ordering::Relaxed);
+
+        Self {
+            backend: backend.clone(),
+        }
+    }
+
+    /// Keep the request outstanding until the response body, e.g. an SSE stream, is sent
+    pub fn attach(self, response: Response) -> Response {
+        response.map(|body| {
+            let mut stream = body.into_data_stream();
+
+            Body::from_stream(async_stream::stream! {
+                let _request = self;
+                while let Some(chunk) = stream.next().await {
+                    yield chunk;
+                }
+            })
+        })
+    }
+}
+
+impl Drop for OutstandingRequest {
+    fn drop(&mut self) {
+        self.backend.outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+
+    use super::*;
+    use crate::config::AiRouterConfigFile;
+    use crate::errors::transform_openai_error_body;
+
+    const TEST_CONFIG_FILE: &str = "tests/ai-router.toml.load_balancing";
+
+    fn select(
+        selector: &BackendSelector,
+        backends: &Backends,
+        config: &AiRouterConfigFile,
+        model_name: &str,
+    ) -> Arc<Backend> {
+        let model = &config.models[&AiRouterModelType::ChatCompletions][model_name];
+
+        selector
+            .select(
+                backends,
+                &AiRouterModelType::ChatCompletions,
+                model_name,
+                model,
+            )
+            .expect("failed to select backend")
+    }
+
+    #[tokio::test]
+    async fn test_round_robin() {
+        let config = AiRouterConfigFile::parse(String::from(TEST_CONFIG_FILE))
+            .expect("failed to load test config file");
+        let backends = Backend::init(&config);
+        let selector = BackendSelector::new(&config.models);
+
+        let selected: Vec<String> = (0..4)
+            .map(|_| {
+                select(&selector, &backends, &config, "round_robin")
+                    .name
+                    .clone()
+            })
+            .collect();
+
+        assert_eq!(
+            selected,
+            ["backend_1", "backend_2", "backend_3", "backend_1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_least_outstanding() {
+        let config = AiRouterConfigFile::parse(String::from(TEST_CONFIG_FILE))
+            .expect("failed to load test config file");
+        let backends = Backend::init(&config);
+        let selector = BackendSelector::new(&config.models);
+
+        let first = OutstandingRequest::new(&backends["backend_1"]);
+        let _second = OutstandingRequest::new(&backends["backend_2"]);
+
+        for _ in 0..3 {
+            let backend = select(&selector, &backends, &config, "least_outstanding");
+            assert_eq!(backend.name, "backend_3");
+        }
+
+        drop(first);
+        let backend = select(&selector, &backends, &config, "least_outstanding");
+        assert_eq!(backend.name, "backend_1");
+    }
+
+    #[tokio::test]
+    async fn test_default_backend() {
+        let config = AiRouterConfigFile::parse(String::from(TEST_CONFIG_FILE))
+            .expect("failed to load test config file");
+        let backends = Backend::init(&config);
+        let selector = BackendSelector::new(&config.models);
+
+        let backend = select(&selector, &backends, &config, "default_backend");
+        assert_eq!(backend.name, "backend_1");
+    }
+
+    fn select_with_fallback(
+        selector: &BackendSelector,
+        backends: &Backends,
+        config: &AiRouterConfigFile,
+        model_name: &str,
+    ) -> Vec<SelectedBackend> {
+        let model = &config.models[&AiRouterModelType::ChatCompletions][model_name];
+
+        selector
+            .select_with_fallback(
+                backends,
+                &AiRouterModelType::ChatCompletions,
+                model_name,
+                model,
+            )
+            .expect("failed to select backends")
+    }
+
+    #[tokio::test]
+    async fn test_select_with_fallback() {
+        let config = AiRouterConfigFile::parse(String::from(TEST_CONFIG_FILE))
+            .expect("failed to load test config file");
+        let backends = Backend::init(&config);
+        let selector = BackendSelector::new(&config.models);
+
+        let selected: Vec<(String, Option<String>)> =
+            select_with_fallback(&selector, &backends, &config, "fallback")
+                .into_iter()
+                .map(|s| (s.backend.name.clone(), s.backend_model))
+                .collect();
+
+        assert_eq!(
+            selected,
+            [
+                (String::from("backend_1"), Some(String::from("primary"))),
+                (String::from("backend_2"), Some(String::from("secondary"))),
+                (String::from("backend_3"), Some(String::from("primary"))),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_with_fallback() {
+        let config = AiRouterConfigFile::parse(String::from(TEST_CONFIG_FILE))
+            .expect("failed to load test config file");
+        let backends = Backend::init(&config);
+        let selector = BackendSelector::new(&config.models);
+
+        let mut attempts: Vec<String> = Vec::new();
+        let selected = select_with_fallback(&selector, &backends, &config, "fallback");
+        let response = send_with_fallback(selected, |selected| {
+            attempts.push(selected.backend.name.clone());
+            async move {
+                if selected.backend.name == "backend_1" {
+                    return Err(AiRouterError::ServiceUnavailable(String::from(
+                        "unreachable",
+                    )));
+                }
+                Ok(StatusCode::OK.into_response())
+            }
+        })
+        .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(attempts, ["backend_1", "backend_2"]);
+
+        let mut attempts: Vec<String> = Vec::new();
+        let selected = select_with_fallback(&selector, &backends, &config, "fallback");
+        let response = send_with_fallback(selected, |selected| {
+            attempts.push(selected.backend.name.clone());
+            async move {
+                if selected.backend.name == "backend_1" {
+                    return Err(AiRouterError::InternalServerError(String::from(
+                        "no tokenizer",
+                    )));
+                }
+                Ok(StatusCode::OK.into_response())
+            }
+        })
+        .await;
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(attempts, ["backend_1"]);
+
+        let mut attempts: Vec<String> = Vec::new();
+        let selected = select_with_fallback(&selector, &backends, &config, "fallback");
+        let response = send_with_fallback(selected, |selected| {
+            attempts.push(selected.backend.name.clone());
+            async move {
+                if selected.backend.name == "backend_1" {
+                    return Err(AiRouterError::BadRequestError(String::from("invalid")));
+                }
+                Ok(StatusCode::OK.into_response())
+            }
+        })
+        .await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(attempts, ["backend_1"]);
+
+        let mut attempts: Vec<String> = Vec::new();
+        let selected = select_with_fallback(&selector, &backends, &config, "fallback");
+        let response = send_with_fallback(selected, |selected| {
+            attempts.push(selected.backend.name.clone());
+            async move {
+                if selected.backend.name == "backend_1" {
+                    return Err(transform_openai_error_body(
+                        r#"{"error": {"message": "rate limited", "type": "requests"}}"#,
+                        StatusCode::TOO_MANY_REQUESTS,
+                    ));
+                }
+                Ok(StatusCode::OK.into_response())
+            }
+        })
+        .await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(attempts, ["backend_1"]);
+        assert!(backends["backend_1"].health.is_available());
+    }
+}
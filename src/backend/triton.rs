@@ -1,6 +1,10 @@
 #![allow(clippy::nursery, clippy::pedantic)]
 tonic::include_proto!("inference");
 
+pub(crate) mod logprobs;
 pub(crate) mod request;
+pub(crate) mod response_format;
 pub mod routes;
+pub(crate) mod stream_decoder;
+pub(crate) mod tool_calls;
 pub(crate) mod utils;
@@ -0,0 +1,411 @@
+//! Log probabilities of the tokens generated by Triton models
+//!
+//! The TensorRT-LLM backend returns the log probability of each generated token in the
+//! `output_log_probs` output when the `return_log_probs` input is set, and the ids of the tokens in
+//! `output_ids`. The ids are decoded with the tokenizer of the model to get the text of each token.
+//! Log probabilities of alternative tokens are not available, so `top_logprobs` can only contain
+//! the generated token.
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use serde_json::Value;
+use tokenizers::Tokenizer;
+
+use super::utils::{get_output_idx, output_sequence_lengths};
+use super::ModelInferResponse;
+use crate::config::AiRouterStreamOutput;
+
+pub const RETURN_LOG_PROBS_INPUT: &str = "return_log_probs";
+pub const OUTPUT_LOG_PROBS_OUTPUT: &str = "output_log_probs";
+pub const OUTPUT_IDS_OUTPUT: &str = "output_ids";
+/// Tokens decoded before a token to get its text, as some tokenizers only add spaces between tokens
+const DECODE_CONTEXT_TOKENS: usize = 4;
+
+/// Ids of generated tokens with their log probabilities
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OutputTokens {
+    pub ids: Vec<u32>,
+    pub logprobs: Vec<f32>,
+}
+
+impl OutputTokens {
+    pub fn extend(&mut self, tokens: Self) {
+        self.ids.extend(tokens.ids);
+        self.logprobs.extend(tokens.logprobs);
+    }
+}
+
+/// Generated token with its log probability
+#[derive(Clone, Debug, PartialEq)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub logprob: f32,
+}
+
+/// `logprobs` of a chat completion choice
+#[derive(Debug, Serialize)]
+struct ChatLogprobs {
+    content: Vec<ChatTokenLogprob>,
+}
+
+#[derive(Debug, Serialize)]
+struct ChatTokenLogprob {
+    token: String,
+    logprob: f32,
+    bytes: Vec<u8>,
+    top_logprobs: Vec<ChatTopLogprob>,
+}
+
+#[derive(Debug, Serialize)]
+struct ChatTopLogprob {
+    token: String,
+    logprob: f32,
+    bytes: Vec<u8>,
+}
+
+/// `logprobs` of a legacy completion choice
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct CompletionLogprobs {
+    pub tokens: Vec<String>,
+    pub token_logprobs: Vec<f32>,
+    pub top_logprobs: Vec<HashMap<String, f32>>,
+    pub text_offset: Vec<usize>,
+}
+
+/// Read the ids and log probabilities of the generated tokens of each beam from a Triton response
+///
+/// Beams are cut to their `sequence_length`, as Triton pads them to the same length.
+///
+/// # Errors
+/// - when the response has no `output_ids` or `output_log_probs`
+pub fn output_tokens(infer_response: &ModelInferResponse) -> Result<Vec<OutputTokens>> {
+    let ids = output_tensor(infer_response, OUTPUT_IDS_OUTPUT, |bytes| {
+        u32::try_from(i32::from_le_bytes(bytes)).unwrap_or_default()
+    })?;
+    let logprobs = output_tensor(infer_response, OUTPUT_LOG_PROBS_OUTPUT, f32::from_le_bytes)?;
+    let sequence_lengths = output_sequence_lengths(infer_response).unwrap_or_default();
+
+    Ok(ids
+        .into_iter()
+        .zip(logprobs)
+        .enumerate()
+        .map(|(beam, (mut ids, mut logprobs))| {
+            let len = sequence_lengths
+                .get(beam)
+                .map_or(usize::MAX, |len| *len as usize)
+                .min(ids.len())
+                .min(logprobs.len());
+            ids.truncate(len);
+            logprobs.truncate(len);
+            OutputTokens { ids, logprobs }
+        })
+        .collect())
+}
+
+/// Tokens of a streamed response that were not received before
+///
+/// Like the text output, the tokens of a response are either all tokens generated so far or only
+/// the new ones. In the first case, the `received` tokens of the previous responses are skipped.
+pub fn new_tokens(
+    mut tokens: OutputTokens,
+    received: usize,
+    mode: AiRouterStreamOutput,
+) -> OutputTokens {
+    if mode == AiRouterStreamOutput::Cumulative {
+        tokens.ids.drain(..received.min(tokens.ids.len()));
+        tokens.logprobs.drain(..received.min(tokens.logprobs.len()));
+    }
+    tokens
+}
+
+/// Read a tensor of 4 byte values with the tokens of each beam in the last dimension
+fn output_tensor<T: Copy>(
+    infer_response: &ModelInferResponse,
+    name: &str,
+    from_le_bytes: fn([u8; 4]) -> T,
+) -> Result<Vec<Vec<T>>> {
+    let Some(idx) = get_output_idx(&infer_response.outputs, name) else {
+        return Err(anyhow!("{name} not found in Triton response"));
+    };
+    let Some(raw_content) = infer_response.raw_output_contents.get(idx) else {
+        return Err(anyhow!("{name} has no contents in Triton response"));
+    };
+
+    let tokens = infer_response.outputs[idx]
+        .shape
+        .last()
+        .map_or(Ok(0), |tokens| usize::try_from(*tokens))?;
+    if tokens == 0 {
+        return Ok(Vec::new());
+    }
+
+    let values: Vec<T> = raw_content
+        .chunks_exact(4)
+        .map(|bytes| from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect();
+    Ok(values.chunks(tokens).map(<[T]>::to_vec).collect())
+}
+
+/// Decode the generated `tokens` and pair them with their log probabilities
+///
+/// `previous` are the ids of the tokens generated before, the text of a token is the text it adds
+/// to the last of them. Special tokens, e.g. the end of sequence token, have an empty text like in
+/// the output text, but keep their entry so every generated token has a log probability.
+///
+/// # Errors
+/// - when the tokenizer fails to decode the ids
+pub fn token_logprobs(
+    tokenizer: &Tokenizer,
+    previous: &[u32],
+    tokens: &OutputTokens,
+) -> Result<Vec<TokenLogprob>> {
+    let decode = |ids: &[u32]| {
+        tokenizer
+            .decode(ids, true)
+            .map_err(|e| anyhow!("failed to decode model output: {e}"))
+    };
+
+    let mut context: Vec<u32> =
+        previous[previous.len().saturating_sub(DECODE_CONTEXT_TOKENS)..].to_vec();
+    let mut token_logprobs: Vec<TokenLogprob> = Vec::new();
+    for (&id, &logprob) in tokens.ids.iter().zip(&tokens.logprobs) {
+        let prefix = decode(&context)?;
+        context.push(id);
+        let text = decode(&context)?;
+        if context.len() > DECODE_CONTEXT_TOKENS {
+            context.remove(0);
+        }
+
+        // the prefix may end with an incomplete character that this token completes
+        let common = prefix
+            .char_indices()
+            .zip(text.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(prefix.len().min(text.len()), |((i, _), _)| i);
+        token_logprobs.push(TokenLogprob {
+            token: String::from(&text[common..]),
+            logprob,
+        });
+    }
+
+    Ok(token_logprobs)
+}
+
+/// `logprobs` of a chat completion choice in the format of `OpenAI`
+///
+/// `top_logprobs` contains the generated token when it is requested, as Triton does not return
+/// alternative tokens, so at most 1 of them can be requested.
+pub fn chat_logprobs(tokens: &[TokenLogprob], top_logprobs: bool) -> Value {
+    let content = tokens
+        .iter()
+        .map(|token| ChatTokenLogprob {
+            token: token.token.clone(),
+            logprob: token.logprob,
+            bytes: token.token.as_bytes().to_vec(),
+            top_logprobs: top_logprobs
+                .then(|| ChatTopLogprob {
+                    token: token.token.clone(),
+                    logprob: token.logprob,
+                    bytes: token.token.as_bytes().to_vec(),
+                })
+                .into_iter()
+                .collect(),
+        })
+        .collect();
+
+    serde_json::to_value(ChatLogprobs { content }).unwrap_or(Value::Null)
+}
+
+/// `logprobs` of a legacy completion choice, with `text_offset` counting characters from `offset`
+/// like `OpenAI`
+pub fn completion_logprobs(tokens: &[TokenLogprob], offset: usize) -> CompletionLogprobs {
+    let mut logprobs = CompletionLogprobs::default();
+    let mut offset = offset;
+
+    for token in tokens {
+        logprobs.tokens.push(token.token.clone());
+        logprobs.token_logprobs.push(token.logprob);
+        logprobs
+            .top_logprobs
+            .push(HashMap::from([(token.token.clone(), token.logprob)]));
+        logprobs.text_offset.push(offset);
+        offset += token.token.chars().count();
+    }
+
+    logprobs
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::backend::triton::model_infer_response::InferOutputTensor;
+
+    const TOKENIZER_FILE: &str = "tests/backend.triton.logprobs.tokenizer";
+
+    fn tokenizer() -> Tokenizer {
+        let tokenizer = std::fs::read_to_string(TOKENIZER_FILE)
+            .unwrap_or_else(|e| panic!("failed to read '{TOKENIZER_FILE}': {e}"));
+        Tokenizer::from_str(&tokenizer).expect("failed to load tokenizer")
+    }
+
+    fn token(token: &str, logprob: f32) -> TokenLogprob {
+        TokenLogprob {
+            token: String::from(token),
+            logprob,
+        }
+    }
+
+    #[test]
+    fn test_output_tokens() {
+        let mut infer_response = ModelInferResponse::default();
+        assert!(output_tokens(&infer_response).is_err());
+
+        let outputs = [
+            (
+                OUTPUT_IDS_OUTPUT,
+                "INT32",
+                vec![1, 2, 2],
+                [1i32, 2, 3, 4].map(i32::to_le_bytes).concat(),
+            ),
+            (
+                OUTPUT_LOG_PROBS_OUTPUT,
+                "FP32",
+                vec![1, 2, 2],
+                [-0.5f32, -1.0, -0.25, -2.0].map(f32::to_le_bytes).concat(),
+            ),
+            (
+                "sequence_length",
+                "INT32",
+                vec![1, 2],
+                [2i32, 1].map(i32::to_le_bytes).concat(),
+            ),
+        ];
+        for (name, datatype, shape, contents) in outputs {
+            infer_response.outputs.push(InferOutputTensor {
+                name: String::from(name),
+                datatype: String::from(datatype),
+                shape,
+                ..Default::default()
+            });
+            infer_response.raw_output_contents.push(contents);
+        }
+
+        assert_eq!(
+            output_tokens(&infer_response).expect("failed to read output tokens"),
+            vec![
+                OutputTokens {
+                    ids: vec![1, 2],
+                    logprobs: vec![-0.5, -1.0]
+                },
+                OutputTokens {
+                    ids: vec![3],
+                    logprobs: vec![-0.25]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_new_tokens() {
+        let tokens = OutputTokens {
+            ids: vec![1, 2, 3],
+            logprobs: vec![-0.5, -1.0, -0.25],
+        };
+
+        assert_eq!(
+            new_tokens(tokens.clone(), 2, AiRouterStreamOutput::Delta),
+            tokens
+        );
+        assert_eq!(
+            new_tokens(tokens.clone(), 2, AiRouterStreamOutput::Cumulative),
+            OutputTokens {
+                ids: vec![3],
+                logprobs: vec![-0.25]
+            }
+        );
+        assert_eq!(
+            new_tokens(tokens, 4, AiRouterStreamOutput::Cumulative),
+            OutputTokens::default()
+        );
+    }
+
+    #[test]
+    fn test_token_logprobs() {
+        let tokens = OutputTokens {
+            ids: vec![1, 2, 3, 4],
+            logprobs: vec![-0.5, -1.0, -0.25, -2.0],
+        };
+        assert_eq!(
+            token_logprobs(&tokenizer(), &[], &tokens).expect("failed to get token logprobs"),
+            vec![
+                token("Paris", -0.5),
+                token(" is", -1.0),
+                token(" sunny", -0.25),
+                token(" .", -2.0)
+            ]
+        );
+
+        let tokens = OutputTokens {
+            ids: vec![2, 3],
+            logprobs: vec![-1.0, -0.25],
+        };
+        assert_eq!(
+            token_logprobs(&tokenizer(), &[1], &tokens).expect("failed to get token logprobs"),
+            vec![token(" is", -1.0), token(" sunny", -0.25)]
+        );
+
+        let tokens = OutputTokens {
+            ids: vec![4, 5],
+            logprobs: vec![-2.0, -0.1],
+        };
+        assert_eq!(
+            token_logprobs(&tokenizer(), &[1], &tokens).expect("failed to get token logprobs"),
+            vec![token(" .", -2.0), token("", -0.1)]
+        );
+    }
+
+    #[test]
+    fn test_chat_logprobs() {
+        let tokens = vec![token("Paris", -0.5), token(" is", -1.0)];
+
+        assert_eq!(
+            chat_logprobs(&tokens, false),
+            json!({
+                "content": [
+                    {"token": "Paris", "logprob": -0.5, "bytes": [80, 97, 114, 105, 115], "top_logprobs": []},
+                    {"token": " is", "logprob": -1.0, "bytes": [32, 105, 115], "top_logprobs": []},
+                ]
+            })
+        );
+        assert_eq!(
+            chat_logprobs(&tokens[1..], true)["content"][0]["top_logprobs"],
+            json!([{"token": " is", "logprob": -1.0, "bytes": [32, 105, 115]}])
+        );
+    }
+
+    #[test]
+    fn test_completion_logprobs() {
+        let tokens = vec![token("Paris", -0.5), token(" is", -1.0)];
+
+        assert_eq!(
+            completion_logprobs(&tokens, 3),
+            CompletionLogprobs {
+                tokens: vec![String::from("Paris"), String::from(" is")],
+                token_logprobs: vec![-0.5, -1.0],
+                top_logprobs: vec![
+                    HashMap::from([(String::from("Paris"), -0.5)]),
+                    HashMap::from([(String::from(" is"), -1.0)])
+                ],
+                text_offset: vec![3, 8],
+            }
+        );
+
+        let tokens = vec![token("Café", -0.5), token(" été", -1.0), token(".", -2.0)];
+        assert_eq!(completion_logprobs(&tokens, 0).text_offset, vec![0, 4, 8]);
+    }
+}
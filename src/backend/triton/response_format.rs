@@ -0,0 +1,175 @@
+//! Structured outputs of Triton chat models
+//!
+//! The `response_format` of a request is passed to backends with guided decoding as the
+//! `guided_decoding_guide_type` and `guided_decoding_guide` inputs of the TensorRT-LLM backend.
+//! Models without guided decoding get the JSON schema in the prompt instead. The output is always
+//! validated, as guided decoding does not apply to tokens generated before it was enabled or
+//! after `max_tokens` cut the output short.
+use jsonschema::Validator;
+use openai_dive::v1::resources::chat::ChatCompletionParameters;
+use serde_json::Value;
+
+use crate::errors::AiRouterError;
+
+/// `json_object` or `json_schema` response format of a chat completion request
+#[derive(Debug)]
+pub enum ResponseFormat {
+    JsonObject,
+    JsonSchema {
+        schema: Value,
+        validator: Box<Validator>,
+    },
+}
+
+impl ResponseFormat {
+    /// Get the response format of a request, `None` for `text`
+    ///
+    /// # Errors
+    /// `AiRouterError::BadRequestError` when the response format is unknown or the JSON schema is
+    /// invalid
+    pub fn from_request(
+        request: &ChatCompletionParameters,
+    ) -> Result<Option<Self>, AiRouterError<String>> {
+        let Some(response_format) = &request.response_format else {
+            return Ok(None);
+        };
+        let response_format = serde_json::to_value(response_format)?;
+
+        match response_format["type"].as_str() {
+            Some("text") => Ok(None),
+            Some("json_object") => Ok(Some(Self::JsonObject)),
+            Some("json_schema") => {
+                let schema = match &response_format["json_schema"]["schema"] {
+                    Value::Null => Value::Object(serde_json::Map::new()),
+                    schema => schema.clone(),
+                };
+                let validator = jsonschema::validator_for(&schema).map_err(|e| {
+                    AiRouterError::BadRequestError(format!(
+                        "invalid JSON schema in response_format: {e}"
+                    ))
+                })?;
+                Ok(Some(Self::JsonSchema {
+                    schema,
+                    validator: Box::new(validator),
+                }))
+            }
+            _ => Err(AiRouterError::BadRequestError(format!(
+                "unsupported response_format: {response_format}"
+            ))),
+        }
+    }
+
+    /// Values of the `guided_decoding_guide_type` and `guided_decoding_guide` Triton inputs
+    pub fn guided_decoding_guide(&self) -> (&'static str, String) {
+        match self {
+            Self::JsonObject => ("json", String::new()),
+            Self::JsonSchema { schema, .. } => ("json_schema", schema.to_string()),
+        }
+    }
+
+    /// Instructions added to the system prompt of models without guided decoding
+    pub fn instructions(&self) -> String {
+        match self {
+            Self::JsonObject => String::from("Respond with a JSON object only."),
+            Self::JsonSchema { schema, .. } => format!(
+                "Respond with a JSON object only, matching the following JSON schema:\n{schema}"
+            ),
+        }
+    }
+
+    /// Check if a model output is valid for the response format
+    ///
+    /// # Errors
+    /// Description of the problems with the output
+    pub fn validate(&self, output: &str) -> Result<(), String> {
+        let value: Value = serde_json::from_str(output.trim())
+            .map_err(|e| format!("model output is not valid JSON: {e}"))?;
+
+        match self {
+            Self::JsonObject if !value.is_object() => {
+                Err(String::from("model output is not a JSON object"))
+            }
+            Self::JsonObject => Ok(()),
+            Self::JsonSchema { validator, .. } => {
+                let errors: Vec<String> = validator
+                    .iter_errors(&value)
+                    .map(|e| format!("{e} at `{}`", e.instance_path))
+                    .collect();
+                if errors.is_empty() {
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "model output does not match the JSON schema: {}",
+                        errors.join(", ")
+                    ))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn request(response_format: Value) -> ChatCompletionParameters {
+        serde_json::from_value(json!({
+            "model": "test",
+            "messages": [{"role": "user", "content": "test"}],
+            "response_format": response_format,
+        }))
+        .expect("failed to deserialize request")
+    }
+
+    #[test]
+    fn test_response_format() {
+        let text = ResponseFormat::from_request(&request(json!({"type": "text"})));
+        assert!(matches!(text, Ok(None)));
+
+        let json_object = ResponseFormat::from_request(&request(json!({"type": "json_object"})))
+            .expect("failed to get response format")
+            .expect("response format missing");
+        assert_eq!(json_object.guided_decoding_guide(), ("json", String::new()));
+        assert!(json_object.validate(" {\"city\": \"Paris\"}\n").is_ok());
+        assert!(json_object.validate("[1, 2]").is_err());
+        assert!(json_object.validate("Paris").is_err());
+
+        let json_schema = ResponseFormat::from_request(&request(json!({
+            "type": "json_schema",
+            "json_schema": {
+                "name": "weather",
+                "schema": {
+                    "type": "object",
+                    "properties": {"temperature": {"type": "number"}},
+                    "required": ["temperature"],
+                },
+            },
+        })))
+        .expect("failed to get response format")
+        .expect("response format missing");
+        assert_eq!(
+            json_schema.guided_decoding_guide(),
+            (
+                "json_schema",
+                String::from(
+                    r#"{"properties":{"temperature":{"type":"number"}},"required":["temperature"],"type":"object"}"#
+                )
+            )
+        );
+        assert!(json_schema.validate(r#"{"temperature": 21.5}"#).is_ok());
+        assert_eq!(
+            json_schema.validate(r#"{"temperature": "warm"}"#),
+            Err(String::from(
+                r#"model output does not match the JSON schema: "warm" is not of type "number" at `/temperature`"#
+            ))
+        );
+
+        let invalid = ResponseFormat::from_request(&request(json!({
+            "type": "json_schema",
+            "json_schema": {"name": "invalid", "schema": {"type": 5}},
+        })));
+        assert!(matches!(invalid, Err(AiRouterError::BadRequestError(_))));
+    }
+}
@@ -1,3 +1,4 @@
+pub(crate) mod audio;
 pub(crate) mod chat;
 pub(crate) mod completions;
 pub mod embeddings;
@@ -0,0 +1,328 @@
+//! <https://platform.openai.com/docs/api-reference/audio/createSpeech>
+//! <https://platform.openai.com/docs/api-reference/audio/createTranscription>
+//! <https://platform.openai.com/docs/api-reference/audio/createTranslation>
+use anyhow::Context;
+use async_stream::stream;
+use axum::response::{IntoResponse, Response};
+use openai_dive::v1::resources::audio::{
+    AudioSpeechParameters, AudioSpeechResponseFormat, AudioTranscriptionParameters,
+};
+use tonic::transport::Channel;
+use tracing;
+use tracing::instrument;
+
+use crate::audio::transcription::{stream_response, Transcription, TranscriptionTask};
+use crate::audio::{
+    decode_upload, encode_pcm, encode_wav, f32_to_i16, DecodedAudio, WHISPER_SAMPLE_RATE,
+};
+use crate::backend::triton::grpc_inference_service_client::GrpcInferenceServiceClient;
+use crate::backend::triton::request::{Builder, InferTensorData};
+use crate::backend::triton::utils::get_output_idx;
+use crate::backend::triton::ModelInferRequest;
+use crate::errors::{transform_triton_status, AiRouterError};
+use crate::utils::deserialize_bytes_tensor;
+
+/// Sample rate of `OpenAI` TTS models
+const DEFAULT_SAMPLE_RATE: u32 = 24_000;
+const MODEL_OUTPUT_NAME: &str = "audio";
+const WHISPER_OUTPUT_NAME: &str = "TRANSCRIPTS";
+
+/// Create speech using a Triton TTS model
+///
+/// The model gets the `text` and `voice` as BYTES and `speed` as FP32 input, and returns mono
+/// audio samples at `sample_rate` in the `audio` output, as FP32 in the range [-1.0, 1.0] or as
+/// INT16. Responses of decoupled models are concatenated.
+///
+/// Only the WAV and PCM response formats are supported, WAV is used if unset.
+#[instrument(skip(client, parameters))]
+pub(crate) async fn speech(
+    mut client: GrpcInferenceServiceClient<Channel>,
+    parameters: AudioSpeechParameters,
+    sample_rate: Option<u32>,
+) -> Result<Response, AiRouterError<String>> {
+    tracing::debug!("triton speech request: {:?}", parameters);
+
+    let response_format = parameters
+        .response_format
+        .clone()
+        .unwrap_or(AudioSpeechResponseFormat::Wav);
+    if !matches!(
+        response_format,
+        AudioSpeechResponseFormat::Pcm | AudioSpeechResponseFormat::Wav
+    ) {
+        return Err(AiRouterError::BadRequestError(format!(
+            "response_format {} not supported for this model, use wav or pcm",
+            serde_plain::to_string(&response_format)?
+        )));
+    }
+
+    let request = build_triton_request(parameters)?;
+    let request_stream = stream! { yield request };
+    let mut stream = client
+        .model_stream_infer(tonic::Request::new(request_stream))
+        .await
+        .map_err(|e| transform_triton_status(&e))?
+        .into_inner();
+
+    let mut samples: Vec<i16> = Vec::new();
+    while let Some(response) = stream
+        .message()
+        .await
+        .map_err(|e| transform_triton_status(&e))?
+    {
+        if !response.error_message.is_empty() {
+            return Err(AiRouterError::BackendError(format!(
+                "error message received from triton: {}",
+                response.error_message
+            )));
+        }
+        let infer_response = response
+            .infer_response
+            .context("empty infer response received")?;
+
+        let Some(idx) = get_output_idx(&infer_response.outputs, MODEL_OUTPUT_NAME) else {
+            return Err(AiRouterError::InternalServerError(format!(
+                "{MODEL_OUTPUT_NAME} not found in Triton response"
+            )));
+        };
+
+        let Some(data) = infer_response.raw_output_contents.get(idx) else {
+            return Err(AiRouterError::InternalServerError(format!(
+                "{MODEL_OUTPUT_NAME} data not found in Triton response"
+            )));
+        };
+        match infer_response.outputs[idx].datatype.as_str() {
+            "FP32" => {
+                let data: Vec<f32> = data
+                    .chunks_exact(4)
+                    .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                    .collect();
+                samples.append(&mut f32_to_i16(&data));
+            }
+            "INT16" => {
+                samples.extend(
+                    data.chunks_exact(2)
+                        .map(|b| i16::from_le_bytes([b[0], b[1]])),
+                );
+            }
+            datatype => {
+                return Err(AiRouterError::InternalServerError(format!(
+                    "unsupported datatype {datatype} of {MODEL_OUTPUT_NAME} in Triton response"
+                )));
+            }
+        }
+    }
+
+    let sample_rate = sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE);
+    let (content_type, audio) = match response_format {
+        AudioSpeechResponseFormat::Pcm => ("audio/pcm", encode_pcm(&samples)),
+        _ => ("audio/wav", encode_wav(&samples, sample_rate)?),
+    };
+
+    Ok(([("content-type", content_type)], audio).into_response())
+}
+
+#[instrument(skip(parameters))]
+fn build_triton_request(parameters: AudioSpeechParameters) -> anyhow::Result<ModelInferRequest> {
+    let voice = serde_plain::to_string(&parameters.voice)?;
+
+    let builder = Builder::new()
+        .model_name(parameters.model)
+        .input(
+            "text",
+            [1, 1],
+            InferTensorData::Bytes(vec![parameters.input.as_bytes().to_vec()]),
+        )
+        .input(
+            "voice",
+            [1, 1],
+            InferTensorData::Bytes(vec![voice.as_bytes().to_vec()]),
+        )
+        .input(
+            "speed",
+            [1, 1],
+            InferTensorData::FP32(vec![parameters.speed.unwrap_or(1.0)]),
+        )
+        .output(MODEL_OUTPUT_NAME);
+
+    builder.build().context("failed to build triton request")
+}
+
+/// Transcribe or translate audio using a Triton Whisper model
+///
+/// The uploaded file is decoded and resampled to 16 kHz mono, see `transcribe`. Streaming
+/// responses get the complete text in a single delta event.
+#[instrument(skip(client, parameters))]
+pub(crate) async fn transcriptions(
+    client: GrpcInferenceServiceClient<Channel>,
+    parameters: AudioTranscriptionParameters,
+    task: TranscriptionTask,
+) -> Result<Response, AiRouterError<String>> {
+    let audio = decode_upload(&parameters.file, WHISPER_SAMPLE_RATE).await?;
+
+    let transcription = transcribe(
+        client,
+        &parameters.model,
+        &audio,
+        task,
+        parameters.language.as_deref(),
+        parameters.prompt.as_deref(),
+    )
+    .await?;
+
+    if parameters.stream == Some(true) {
+        let events = transcription.into_stream_events().into_iter().map(Ok);
+        return stream_response(futures::stream::iter(events)).await;
+    }
+
+    Ok(transcription.into_response(parameters.response_format.as_ref()))
+}
+
+/// Transcribe or translate 16 kHz mono audio using a Triton Whisper model
+///
+/// The model gets the samples as FP32 `WAV` input with their number in the INT32 `WAV_LENS`
+/// input, and the decoder prompt in the BYTES `TEXT_PREFIX` input. The text with timestamp tokens
+/// is returned in the BYTES `TRANSCRIPTS` output.
+pub(crate) async fn transcribe(
+    mut client: GrpcInferenceServiceClient<Channel>,
+    model: &str,
+    audio: &DecodedAudio,
+    task: TranscriptionTask,
+    language: Option<&str>,
+    prompt: Option<&str>,
+) -> Result<Transcription, AiRouterError<String>> {
+    let request = build_whisper_request(model, audio, task, language, prompt)?;
+    let request_stream = stream! { yield request };
+    let mut stream = client
+        .model_stream_infer(tonic::Request::new(request_stream))
+        .await
+        .map_err(|e| transform_triton_status(&e))?
+        .into_inner();
+
+    let mut output = String::new();
+    while let Some(response) = stream
+        .message()
+        .await
+        .map_err(|e| transform_triton_status(&e))?
+    {
+        if !response.error_message.is_empty() {
+            return Err(AiRouterError::BackendError(format!(
+                "error message received from triton: {}",
+                response.error_message
+            )));
+        }
+        let infer_response = response
+            .infer_response
+            .context("empty infer response received")?;
+
+        let Some(idx) = get_output_idx(&infer_response.outputs, WHISPER_OUTPUT_NAME) else {
+            return Err(AiRouterError::InternalServerError(format!(
+                "{WHISPER_OUTPUT_NAME} not found in Triton response"
+            )));
+        };
+
+        let Some(data) = infer_response.raw_output_contents.get(idx) else {
+            return Err(AiRouterError::InternalServerError(format!(
+                "{WHISPER_OUTPUT_NAME} data not found in Triton response"
+            )));
+        };
+        output.push_str(&deserialize_bytes_tensor(data.clone())?.concat());
+    }
+
+    tracing::debug!("whisper output: {output}");
+
+    Ok(Transcription::from_whisper_output(
+        &output,
+        task,
+        language,
+        audio.duration(),
+    ))
+}
+
+#[instrument(skip(audio))]
+fn build_whisper_request(
+    model: &str,
+    audio: &DecodedAudio,
+    task: TranscriptionTask,
+    language: Option<&str>,
+    prompt: Option<&str>,
+) -> anyhow::Result<ModelInferRequest> {
+    let num_samples = i32::try_from(audio.samples.len())?;
+    let text_prefix = build_whisper_prefix(task, language, prompt);
+
+    let builder = Builder::new()
+        .model_name(model)
+        .input(
+            "WAV",
+            [1, i64::from(num_samples)],
+            InferTensorData::FP32(audio.samples.clone()),
+        )
+        .input(
+            "WAV_LENS",
+            [1, 1],
+            InferTensorData::Int32(vec![num_samples]),
+        )
+        .input(
+            "TEXT_PREFIX",
+            [1, 1],
+            InferTensorData::Bytes(vec![text_prefix.into_bytes()]),
+        )
+        .output(WHISPER_OUTPUT_NAME);
+
+    builder.build().context("failed to build triton request")
+}
+
+/// Whisper decoder prompt
+///
+/// The task token follows the language token, so without a language the prompt ends at
+/// `<|startoftranscript|>` and the model detects the language and predicts the task.
+fn build_whisper_prefix(
+    task: TranscriptionTask,
+    language: Option<&str>,
+    prompt: Option<&str>,
+) -> String {
+    let mut prefix = String::new();
+
+    if let Some(prompt) = prompt {
+        prefix.push_str(&format!("<|startofprev|> {}", prompt.trim()));
+    }
+    prefix.push_str("<|startoftranscript|>");
+    if let Some(language) = language {
+        prefix.push_str(&format!("<|{language}|>"));
+        match task {
+            TranscriptionTask::Transcribe => prefix.push_str("<|transcribe|>"),
+            TranscriptionTask::Translate => prefix.push_str("<|translate|>"),
+        }
+    }
+
+    prefix
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_whisper_prefix() {
+        assert_eq!(
+            build_whisper_prefix(TranscriptionTask::Transcribe, None, None),
+            "<|startoftranscript|>"
+        );
+        assert_eq!(
+            build_whisper_prefix(TranscriptionTask::Translate, None, Some("Glossary: AI")),
+            "<|startofprev|> Glossary: AI<|startoftranscript|>"
+        );
+        assert_eq!(
+            build_whisper_prefix(TranscriptionTask::Transcribe, Some("en"), None),
+            "<|startoftranscript|><|en|><|transcribe|>"
+        );
+        assert_eq!(
+            build_whisper_prefix(
+                TranscriptionTask::Translate,
+                Some("de"),
+                Some("Glossar: AI")
+            ),
+            "<|startofprev|> Glossar: AI<|startoftranscript|><|de|><|translate|>"
+        );
+    }
+}
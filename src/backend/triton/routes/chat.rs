@@ -1,32 +1,48 @@
 //! <https://platform.openai.com/docs/api-reference/chat/create>
 use std::iter::IntoIterator;
+use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::Context;
 use async_stream::{stream, try_stream};
+use axum::http::StatusCode;
 use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::{IntoResponse, Response};
 use axum::Json;
 use openai_dive::v1::resources::chat::{
     ChatCompletionChoice, ChatCompletionChunkChoice, ChatCompletionChunkResponse,
-    ChatCompletionParameters, ChatCompletionResponse, ChatMessage, ChatMessageContent,
-    DeltaChatMessage,
+    ChatCompletionParameters, ChatCompletionResponse, ChatCompletionTool, ChatCompletionToolChoice,
+    ChatMessage, ChatMessageContent, DeltaChatMessage, DeltaFunction, DeltaToolCall, Function,
+    ToolCall,
 };
-use openai_dive::v1::resources::shared::{FinishReason, StopToken, Usage};
-use serde_json::json;
-use tonic::codegen::tokio_stream::Stream;
+use openai_dive::v1::resources::shared::{FinishReason, StopToken};
+use serde_json::{json, Value};
+use tokenizers::Tokenizer;
+use tonic::codegen::tokio_stream::{Stream, StreamExt};
 use tonic::transport::Channel;
 use tracing;
 use tracing::instrument;
 use uuid::Uuid;
 
 use crate::backend::triton::grpc_inference_service_client::GrpcInferenceServiceClient;
+use crate::backend::triton::logprobs::{
+    chat_logprobs, new_tokens, output_tokens, token_logprobs, OutputTokens, TokenLogprob,
+    OUTPUT_IDS_OUTPUT, OUTPUT_LOG_PROBS_OUTPUT, RETURN_LOG_PROBS_INPUT,
+};
 use crate::backend::triton::request::{Builder, InferTensorData};
-use crate::backend::triton::utils::get_output_idx;
+use crate::backend::triton::response_format::ResponseFormat;
+use crate::backend::triton::stream_decoder::StreamDecoder;
+use crate::backend::triton::tool_calls::{may_be_tool_call, parse_tool_calls, ParsedToolCall};
+use crate::backend::triton::utils::{
+    add_sequence_length, check_n, count_completion_tokens, finish_reason, get_output_idx,
+    output_sequence_lengths, sample_requests, stream_infer, SEQUENCE_LENGTH_OUTPUT,
+};
 use crate::backend::triton::ModelInferRequest;
-use crate::errors::AiRouterError;
+use crate::config::AiRouterStreamOutput;
+use crate::errors::{transform_triton_status, AiRouterError};
 use crate::request::{check_input_cc, AiRouterRequestData};
-use crate::utils::deserialize_bytes_tensor;
+use crate::templates::{PromptMessage, PromptTemplate};
+use crate::utils::{deserialize_bytes_tensor, split_bytes_tensor};
 
 const MAX_TOKENS: u32 = 131_072;
 const MODEL_OUTPUT_NAME: &str = "text_output";
@@ -52,31 +68,65 @@ pub async fn compat_chat_completions(
 
 #[instrument(skip(client, request, request_data))]
 async fn chat_completions_stream(
-    mut client: GrpcInferenceServiceClient<Channel>,
+    client: GrpcInferenceServiceClient<Channel>,
     Json(request): Json<ChatCompletionParameters>,
     request_data: &mut AiRouterRequestData,
 ) -> Result<Sse<impl Stream<Item = anyhow::Result<Event>>>, AiRouterError<String>> {
     let id = format!("cmpl-{}", Uuid::new_v4());
     let created = u32::try_from(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())?;
 
-    let request = build_triton_request(request, request_data)?;
+    let include_usage = request
+        .stream_options
+        .as_ref()
+        .and_then(|stream_options| stream_options.include_usage)
+        .unwrap_or(false);
+    let (requests, options) = prepare_requests(request, request_data)?;
     let model_name = request_data
         .original_model
         .clone()
-        .unwrap_or(request.model_name.clone());
+        .unwrap_or(requests[0].model_name.clone());
+    let request_data = request_data.clone();
 
-    let response_stream = try_stream! {
-        let request_stream = stream! { yield request };
+    let mut choices: Vec<StreamedChoice> = requests
+        .iter()
+        .map(|_| StreamedChoice::new(options.tool_names.is_some(), request_data.stream_output))
+        .collect();
+    let mut stream = stream_infer(&client, requests).await?;
 
-        let mut stream = client
-            .model_stream_infer(tonic::Request::new(request_stream))
-            .await
-            .context("failed to call triton grpc method model_stream_infer")?
-            .into_inner();
+    let response_stream = try_stream! {
+        let mut completion_tokens = 0;
 
-        let mut content_prev = String::new();
+        while let Some((index, response)) = stream.next().await {
+            let choice_index = u32::try_from(index)?;
+            let Some(response) = response else {
+                match choices[index].finish(&options, &request_data) {
+                    Ok((deltas, finish_reason, choice_tokens)) => {
+                        completion_tokens += choice_tokens;
+                        for (delta, logprobs) in deltas {
+                            let mut response = chunk_response(&id, created, &model_name, choice_index, delta, None);
+                            response.choices[0].logprobs = logprobs.map(serde_json::from_value).transpose()?;
+                            yield Event::default().json_data(response)?;
+                        }
+                        let delta = delta_empty();
+                        let response = chunk_response(&id, created, &model_name, choice_index, delta, Some(finish_reason));
+                        yield Event::default().json_data(response)?;
+                    }
+                    // the output was already sent to the client, so it can only be rejected after the fact
+                    Err((status_code, e)) => {
+                        tracing::error!("{e}");
+                        yield Event::default().event("error").json_data(json!({
+                            "error": {
+                                "status_code": status_code.as_u16(),
+                                "message": e
+                            }
+                        }))?;
+                        return;
+                    }
+                }
+                continue;
+            };
 
-        while let Some(response) = stream.message().await? {
+            let response = response?;
             if !response.error_message.is_empty() {
                 tracing::error!("received error message from triton: {}", response.error_message);
 
@@ -104,64 +154,29 @@ async fn chat_completions_stream(
             };
 
             let raw_content = infer_response.raw_output_contents[idx].clone();
-            let content = deserialize_bytes_tensor(raw_content)?
-                .into_iter()
-                .map(|s| s.replace("</s>", ""))
-                .collect::<String>();
-
-            if !content.is_empty() {
-                let content_new = content.replace(&content_prev, "");
-                if content_new.is_empty() {
-                    continue;
-                }
-                content_prev.clone_from(&content);
-                let response = ChatCompletionChunkResponse {
-                    id: Some(id.clone()),
-                    object: String::from("chat.completion.chunk"),
-                    created,
-                    model: model_name.clone(),
-                    system_fingerprint: None,
-                    usage: None,
-                    choices: vec![ChatCompletionChunkChoice {
-                        index: Some(0),
-                        delta: DeltaChatMessage::Assistant {
-                            content: Some(ChatMessageContent::Text(content_new)),
-                            reasoning: None,
-                            reasoning_content: None,
-                            refusal: None,
-                            name: None,
-                            tool_calls: None,
-                        },
-                        finish_reason: None,
-                        logprobs: None,
-                    }],
-                };
+            let output = split_bytes_tensor(raw_content).concat();
+            let tokens = if options.logprobs.is_some() {
+                output_tokens(&infer_response)?.into_iter().next().unwrap_or_default()
+            } else {
+                OutputTokens::default()
+            };
+            let sequence_length = output_sequence_lengths(&infer_response)
+                .and_then(|lengths| lengths.first().copied());
+
+            let delta = choices[index].push(&output, tokens, sequence_length, &options)?;
+            if let Some((delta, logprobs)) = delta {
+                let mut response = chunk_response(&id, created, &model_name, choice_index, delta, None);
+                response.choices[0].logprobs = logprobs.map(serde_json::from_value).transpose()?;
                 yield Event::default().json_data(response)?;
             }
         }
-        let response = ChatCompletionChunkResponse {
-            id: Some(id),
-            object: String::from("chat.completion.chunk"),
-            created,
-            model: model_name,
-            system_fingerprint: None,
-            usage: None,
-            choices: vec![ChatCompletionChunkChoice {
-                index: Some(0),
-                delta: DeltaChatMessage::Untagged {
-                    content: None,
-                    reasoning: None,
-                    reasoning_content: None,
-                    refusal: None,
-                    name: None,
-                    tool_calls: None,
-                    tool_call_id: None,
-                },
-                finish_reason: Some(FinishReason::StopSequenceReached),
-                logprobs: None,
-            }],
-        };
-        yield Event::default().json_data(response)?;
+
+        if include_usage {
+            let mut response = chunk_response(&id, created, &model_name, 0, delta_empty(), None);
+            response.choices.clear();
+            response.usage = Some(request_data.usage(completion_tokens));
+            yield Event::default().json_data(response)?;
+        }
 
         // OpenAI stream response terminated by a data: [DONE] message.
         yield Event::default().data("[DONE]");
@@ -172,26 +187,141 @@ async fn chat_completions_stream(
 
 #[instrument(skip(client, request, request_data), err(Debug))]
 async fn chat_completions(
-    mut client: GrpcInferenceServiceClient<Channel>,
+    client: GrpcInferenceServiceClient<Channel>,
     Json(request): Json<ChatCompletionParameters>,
     request_data: &mut AiRouterRequestData,
 ) -> Result<Json<ChatCompletionResponse>, AiRouterError<String>> {
-    let request = build_triton_request(request, request_data)?;
+    let (requests, options) = prepare_requests(request, request_data)?;
     let model_name = request_data
         .original_model
         .clone()
-        .unwrap_or(request.model_name.clone());
+        .unwrap_or(requests[0].model_name.clone());
+
+    let request_data = &*request_data;
+    let choices =
+        futures::future::try_join_all(requests.into_iter().zip(0..).map(|(request, index)| {
+            generate_choice(client.clone(), request, index, &options, request_data)
+        }))
+        .await?;
+    let completion_tokens = choices.iter().map(|(_, tokens)| tokens).sum();
+
+    Ok(Json(ChatCompletionResponse {
+        id: Some(format!("cmpl-{}", Uuid::new_v4())),
+        object: String::from("chat.completion"),
+        created: u32::try_from(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())?,
+        model: model_name,
+        service_tier: None,
+        system_fingerprint: None,
+        choices: choices.into_iter().map(|(choice, _)| choice).collect(),
+        usage: Some(request_data.usage(completion_tokens)),
+    }))
+}
+
+/// Generate a choice, repeating the request when the output does not match the `response_format`
+///
+/// Returns the choice and its number of tokens.
+async fn generate_choice(
+    mut client: GrpcInferenceServiceClient<Channel>,
+    request: ModelInferRequest,
+    index: u32,
+    options: &OutputOptions,
+    request_data: &AiRouterRequestData,
+) -> Result<(ChatCompletionChoice, u32), AiRouterError<String>> {
+    let mut retries = request_data.response_format_retries;
+    let mut completion_tokens;
+    let mut logprobs;
+    let (content, tool_calls) = loop {
+        let (content, tokens, sequence_length) =
+            infer(&mut client, request.clone(), options.logprobs.is_some()).await?;
+        completion_tokens = count_completion_tokens(sequence_length, &content, request_data);
+        logprobs = match &options.logprobs {
+            Some(tokenizer) => Some(serde_json::from_value(chat_logprobs(
+                &token_logprobs(tokenizer, &[], &tokens)?,
+                options.top_logprobs,
+            ))?),
+            None => None,
+        };
+        if let Some((content, tool_calls)) = options
+            .tool_names
+            .as_ref()
+            .and_then(|tool_names| parse_tool_calls(&content, tool_names))
+        {
+            let tool_calls = tool_calls.into_iter().map(to_tool_call).collect::<Vec<_>>();
+            break (content, Some(tool_calls));
+        }
+
+        let e = if options.require_tool_call {
+            String::from("the output does not call a tool, but tool_choice requires it")
+        } else {
+            let Some(Err(e)) = options
+                .response_format
+                .as_ref()
+                .map(|f| f.validate(&content))
+            else {
+                break (Some(content), None);
+            };
+            e
+        };
+        if retries == 0 {
+            return Err(AiRouterError::InvalidOutput(e));
+        }
+        retries -= 1;
+        tracing::warn!("repeating request, {e}");
+    };
+    let finish_reason = match &content {
+        _ if tool_calls.is_some() => FinishReason::ToolCalls,
+        Some(content) => finish_reason(
+            content,
+            &options.stop,
+            completion_tokens,
+            options.max_tokens,
+        ),
+        None => FinishReason::StopSequenceReached,
+    };
+
+    let choice = ChatCompletionChoice {
+        index,
+        message: ChatMessage::Assistant {
+            content: content.map(ChatMessageContent::Text),
+            reasoning: None,
+            reasoning_content: None,
+            refusal: None,
+            name: None,
+            audio: None,
+            tool_calls,
+        },
+        finish_reason: Some(finish_reason),
+        logprobs,
+    };
+
+    Ok((choice, completion_tokens))
+}
+
+/// Send a request to Triton and collect the complete output, its tokens with their log
+/// probabilities when `logprobs` is set, and its number of tokens when Triton returns a
+/// `sequence_length`
+async fn infer(
+    client: &mut GrpcInferenceServiceClient<Channel>,
+    request: ModelInferRequest,
+    logprobs: bool,
+) -> Result<(String, OutputTokens, Option<u32>), AiRouterError<String>> {
     let request_stream = stream! { yield request };
     let mut stream = client
         .model_stream_infer(tonic::Request::new(request_stream))
         .await
-        .context("failed to call triton grpc method model_stream_infer")?
+        .map_err(|e| transform_triton_status(&e))?
         .into_inner();
 
     let mut contents: Vec<String> = Vec::new();
-    while let Some(response) = stream.message().await? {
+    let mut tokens = OutputTokens::default();
+    let mut sequence_length = Some(0);
+    while let Some(response) = stream
+        .message()
+        .await
+        .map_err(|e| transform_triton_status(&e))?
+    {
         if !response.error_message.is_empty() {
-            return Err(AiRouterError::InternalServerError(format!(
+            return Err(AiRouterError::BackendError(format!(
                 "error message received from triton: {}",
                 response.error_message
             )));
@@ -208,51 +338,276 @@ async fn chat_completions(
         };
 
         let raw_content = infer_response.raw_output_contents[idx].clone();
-        let content = deserialize_bytes_tensor(raw_content)?
+        contents.push(deserialize_bytes_tensor(raw_content)?.concat());
+
+        if logprobs {
+            let beam_tokens = output_tokens(&infer_response)?.into_iter().next();
+            tokens.extend(beam_tokens.unwrap_or_default());
+        }
+        sequence_length = add_sequence_length(
+            sequence_length,
+            output_sequence_lengths(&infer_response).and_then(|lengths| lengths.first().copied()),
+            AiRouterStreamOutput::Delta,
+        );
+    }
+
+    // on the complete output, as the end of sequence token can be split across responses
+    let content = contents.concat().replace("</s>", "");
+
+    Ok((content, tokens, sequence_length))
+}
+
+/// Settings turning the outputs of a request into choices
+struct OutputOptions {
+    max_tokens: u32,
+    /// Names of the functions the model can call, `None` when the output is not parsed for tool
+    /// calls
+    tool_names: Option<Vec<String>>,
+    /// `tool_choice` requires the model to call a tool, outputs without tool calls are rejected
+    require_tool_call: bool,
+    response_format: Option<ResponseFormat>,
+    stop: Vec<String>,
+    /// Tokenizer splitting the outputs into tokens when `logprobs` are requested
+    logprobs: Option<Arc<Tokenizer>>,
+    top_logprobs: bool,
+}
+
+/// Build a Triton request for each of the `n` choices of a request
+///
+/// # Errors
+/// `AiRouterError::BadRequestError` when `n` is invalid, see `check_n`, when `logprobs` are
+/// requested for a model without tokenizer, or when `top_logprobs` is more than 1
+fn prepare_requests(
+    request: ChatCompletionParameters,
+    request_data: &mut AiRouterRequestData,
+) -> Result<(Vec<ModelInferRequest>, OutputOptions), AiRouterError<String>> {
+    let n = check_n(
+        usize::try_from(request.n.unwrap_or(1))?,
+        request_data.max_n,
+        request.temperature,
+        false,
+    )?;
+
+    let max_tokens = resolve_max_tokens(
+        request.max_completion_tokens,
+        request.max_tokens,
+        request_data.max_tokens,
+    );
+    let stop = stop_words(request.stop.as_ref());
+    let logprobs = if request.logprobs.unwrap_or(false) {
+        let Some(tokenizer) = request_data.tokenizer.clone() else {
+            return Err(AiRouterError::BadRequestError(format!(
+                "logprobs are not supported for model {}, as it has no hf_model_name",
+                request.model
+            )));
+        };
+        Some(tokenizer)
+    } else {
+        None
+    };
+    let top_logprobs = match request.top_logprobs {
+        Some(top_logprobs) if top_logprobs > 1 => {
+            return Err(AiRouterError::BadRequestError(String::from(
+                "top_logprobs greater than 1 is not supported, Triton only returns the generated token",
+            )));
+        }
+        top_logprobs => top_logprobs == Some(1),
+    };
+    let tools = get_tools(&request)?;
+    let tool_names = tools.as_ref().map(|tools| {
+        tools
+            .iter()
+            .filter_map(|tool| tool["function"]["name"].as_str().map(String::from))
+            .collect()
+    });
+    let require_tool_call = matches!(
+        request.tool_choice,
+        Some(
+            ChatCompletionToolChoice::Required
+                | ChatCompletionToolChoice::ChatCompletionToolChoiceFunction(_)
+        )
+    );
+    let response_format = ResponseFormat::from_request(&request)?;
+    let request = build_triton_request(
+        request,
+        tools.as_deref(),
+        response_format.as_ref(),
+        request_data,
+    )?;
+
+    let options = OutputOptions {
+        max_tokens,
+        tool_names,
+        require_tool_call,
+        response_format,
+        stop,
+        logprobs,
+        top_logprobs,
+    };
+    Ok((sample_requests(request, n), options))
+}
+
+/// Delta of a streamed choice with its `logprobs`
+type ChoiceDelta = (DeltaChatMessage, Option<Value>);
+
+/// Output of a choice received so far while streaming
+struct StreamedChoice {
+    decoder: StreamDecoder,
+    output: String,
+    /// Output that may be tool calls is held back until it is complete, only tool calls at the
+    /// start of the output are detected while streaming
+    tool_call_buffer: Option<String>,
+    /// Tokens of the output that was not sent yet
+    logprobs: Vec<TokenLogprob>,
+    /// Ids of the tokens generated so far, when `logprobs` are requested
+    output_ids: Vec<u32>,
+    stream_output: AiRouterStreamOutput,
+    /// Number of tokens generated so far, `None` when Triton does not return `sequence_length`
+    sequence_length: Option<u32>,
+}
+
+impl StreamedChoice {
+    fn new(parse_tools: bool, stream_output: AiRouterStreamOutput) -> Self {
+        Self {
+            decoder: StreamDecoder::new(stream_output),
+            output: String::new(),
+            tool_call_buffer: parse_tools.then(String::new),
+            logprobs: Vec::new(),
+            output_ids: Vec::new(),
+            stream_output,
+            sequence_length: Some(0),
+        }
+    }
+
+    /// Add the output of a Triton response with its tokens and their number, returns the delta to
+    /// send to the client with its `logprobs` when requested
+    ///
+    /// # Errors
+    /// - when the tokenizer fails to decode the tokens
+    fn push(
+        &mut self,
+        output: &[u8],
+        tokens: OutputTokens,
+        sequence_length: Option<u32>,
+        options: &OutputOptions,
+    ) -> anyhow::Result<Option<ChoiceDelta>> {
+        if let Some(tokenizer) = &options.logprobs {
+            let tokens = new_tokens(tokens, self.output_ids.len(), self.stream_output);
+            self.logprobs
+                .extend(token_logprobs(tokenizer, &self.output_ids, &tokens)?);
+            self.output_ids.extend(tokens.ids);
+        }
+        self.sequence_length =
+            add_sequence_length(self.sequence_length, sequence_length, self.stream_output);
+        let content = self.decoder.push(output);
+        self.push_content(content, options)
+    }
+
+    /// Add newly decoded content, returns the delta to send to the client
+    fn push_content(
+        &mut self,
+        mut content_new: String,
+        options: &OutputOptions,
+    ) -> anyhow::Result<Option<ChoiceDelta>> {
+        if content_new.is_empty() {
+            return Ok(None);
+        }
+        self.output.push_str(&content_new);
+
+        if let Some(buffer) = &mut self.tool_call_buffer {
+            buffer.push_str(&content_new);
+            // with a required tool call, the output is only sent once it is parsed as tool calls
+            if options.require_tool_call || may_be_tool_call(buffer) {
+                return Ok(None);
+            }
+            content_new = std::mem::take(buffer);
+            self.tool_call_buffer = None;
+        }
+
+        let logprobs = self.take_logprobs(options);
+        Ok(Some((content_delta(content_new), logprobs)))
+    }
+
+    /// `logprobs` of the tokens not sent yet, `None` when they are not requested
+    fn take_logprobs(&mut self, options: &OutputOptions) -> Option<Value> {
+        let tokens = std::mem::take(&mut self.logprobs);
+        options
+            .logprobs
+            .as_ref()
+            .map(|_| chat_logprobs(&tokens, options.top_logprobs))
+    }
+
+    /// Complete the choice after the last Triton response, returns the remaining deltas, the
+    /// finish reason and the number of tokens of the choice
+    ///
+    /// # Errors
+    /// Status code and description of the error
+    /// - `422 Unprocessable Entity` with the problems with the output when it does not match the
+    ///   `response_format` or does not call a tool required by `tool_choice`
+    /// - `500 Internal Server Error` with the tokenizer error when the rest of the output could
+    ///   not be split into tokens
+    fn finish(
+        &mut self,
+        options: &OutputOptions,
+        request_data: &AiRouterRequestData,
+    ) -> Result<(Vec<ChoiceDelta>, FinishReason, u32), (StatusCode, String)> {
+        let content = self.decoder.finish();
+        let mut deltas: Vec<_> = self
+            .push_content(content, options)
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
             .into_iter()
-            .map(|s| s.replace("</s>", ""))
             .collect();
-        contents.push(content);
-    }
 
-    let prompt_tokens = request_data.prompt_tokens.try_into().unwrap_or(0);
+        let completion_tokens =
+            count_completion_tokens(self.sequence_length, &self.output, request_data);
+        let mut finish_reason = finish_reason(
+            &self.output,
+            &options.stop,
+            completion_tokens,
+            options.max_tokens,
+        );
 
-    Ok(Json(ChatCompletionResponse {
-        id: Some(format!("cmpl-{}", Uuid::new_v4())),
-        object: String::from("chat.completion"),
-        created: u32::try_from(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())?,
-        model: model_name,
-        service_tier: None,
-        system_fingerprint: None,
-        choices: vec![ChatCompletionChoice {
-            index: 0,
-            message: ChatMessage::Assistant {
-                content: Some(ChatMessageContent::Text(contents.into_iter().collect())),
-                reasoning: None,
-                reasoning_content: None,
-                refusal: None,
-                name: None,
-                audio: None,
-                tool_calls: None,
-            },
-            finish_reason: Some(FinishReason::StopSequenceReached),
-            logprobs: None,
-        }],
-        // Not fully supported yet, need Triton to return usage stats
-        // but populate prompt_tokens and total_tokens for models configured with max_tokens
-        usage: Some(Usage {
-            prompt_tokens: Some(prompt_tokens),
-            completion_tokens: Some(0),
-            // add completion_tokens once we can get them from Triton
-            total_tokens: prompt_tokens,
-            prompt_tokens_details: None,
-            completion_tokens_details: None,
-        }),
-    }))
+        if let Some(buffer) = self.tool_call_buffer.take() {
+            let tool_names = options.tool_names.as_deref().unwrap_or_default();
+            let (content, tool_calls) = match parse_tool_calls(&buffer, tool_names) {
+                Some((content, tool_calls)) => (content, Some(tool_calls)),
+                None => ((!buffer.is_empty()).then_some(buffer), None),
+            };
+            let logprobs = self.take_logprobs(options);
+            deltas.extend(content.map(|content| (content_delta(content), logprobs)));
+            if let Some(tool_calls) = tool_calls {
+                deltas.push((tool_calls_delta(tool_calls), None));
+                finish_reason = FinishReason::ToolCalls;
+            }
+        }
+        // tokens without text, e.g. the end of sequence token, are sent without content
+        if !self.logprobs.is_empty() {
+            let logprobs = self.take_logprobs(options);
+            deltas.push((delta_empty(), logprobs));
+        }
+        if options.require_tool_call && !matches!(finish_reason, FinishReason::ToolCalls) {
+            return Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                String::from("the output does not call a tool, but tool_choice requires it"),
+            ));
+        }
+
+        if let Some(response_format) = &options.response_format {
+            if !matches!(finish_reason, FinishReason::ToolCalls) {
+                response_format
+                    .validate(&self.output)
+                    .map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e))?;
+            }
+        }
+
+        Ok((deltas, finish_reason, completion_tokens))
+    }
 }
 
 fn build_triton_request(
     request: ChatCompletionParameters,
+    tools: Option<&[Value]>,
+    response_format: Option<&ResponseFormat>,
     request_data: &mut AiRouterRequestData,
 ) -> Result<ModelInferRequest, AiRouterError<String>> {
     let max_tokens = resolve_max_tokens(
@@ -260,7 +615,16 @@ fn build_triton_request(
         request.max_tokens,
         request_data.max_tokens,
     );
-    let chat_history = build_chat_history(request.messages);
+    let guided_decoding = response_format.filter(|_| request_data.guided_decoding);
+    let instructions = response_format
+        .filter(|_| guided_decoding.is_none())
+        .map(ResponseFormat::instructions);
+    let chat_history = build_chat_history(
+        request.messages,
+        tools,
+        instructions.as_deref(),
+        request_data.prompt_template.as_ref(),
+    )?;
     tracing::debug!("chat history after formatting: {}", chat_history);
 
     check_input_cc(&chat_history, &request.model, request_data)?;
@@ -287,15 +651,8 @@ fn build_triton_request(
             [1, 1],
             InferTensorData::Bool(vec![request.stream.unwrap_or(false)]),
         )
-        .output(MODEL_OUTPUT_NAME);
-
-    if let Some(beam_width) = request.n {
-        builder = builder.input(
-            "beam_width",
-            [1, 1],
-            InferTensorData::Int32(vec![i32::try_from(beam_width)?]),
-        );
-    }
+        .output(MODEL_OUTPUT_NAME)
+        .output(SEQUENCE_LENGTH_OUTPUT);
 
     if let Some(presence_penalty) = request.presence_penalty {
         builder = builder.input(
@@ -333,9 +690,43 @@ fn build_triton_request(
         builder = builder.input("top_p", [1, 1], InferTensorData::FP32(vec![top_p]));
     }
 
+    if request.logprobs.unwrap_or(false) {
+        builder = builder
+            .input(
+                RETURN_LOG_PROBS_INPUT,
+                [1, 1],
+                InferTensorData::Bool(vec![true]),
+            )
+            .output(OUTPUT_LOG_PROBS_OUTPUT)
+            .output(OUTPUT_IDS_OUTPUT);
+    }
+
+    if let Some(response_format) = guided_decoding {
+        let (guide_type, guide) = response_format.guided_decoding_guide();
+        builder = builder
+            .input(
+                "guided_decoding_guide_type",
+                [1, 1],
+                InferTensorData::Bytes(vec![guide_type.as_bytes().to_vec()]),
+            )
+            .input(
+                "guided_decoding_guide",
+                [1, 1],
+                InferTensorData::Bytes(vec![guide.into_bytes()]),
+            );
+    }
+
     Ok(builder.build().context("failed to build triton request")?)
 }
 
+fn stop_words(stop: Option<&StopToken>) -> Vec<String> {
+    match stop {
+        Some(StopToken::Array(a)) => a.clone(),
+        Some(StopToken::String(s)) => vec![s.clone()],
+        None => Vec::new(),
+    }
+}
+
 fn resolve_max_tokens(
     max_completion_tokens: Option<u32>,
     max_tokens: Option<u32>,
@@ -347,27 +738,242 @@ fn resolve_max_tokens(
         .unwrap_or(MAX_TOKENS)
 }
 
-fn build_chat_history(messages: Vec<ChatMessage>) -> String {
+/// Tools the model can call, `None` if the request has no tools or `tool_choice` is `none`
+///
+/// When `tool_choice` names a function, the model only gets that function.
+///
+/// # Errors
+/// `AiRouterError::BadRequestError` when `tool_choice` requires a tool call but there are no
+/// tools, or names a function that is not in `tools`
+fn get_tools(
+    request: &ChatCompletionParameters,
+) -> Result<Option<Vec<Value>>, AiRouterError<String>> {
+    let tools = request.tools.as_deref().unwrap_or_default();
+    let tools: Vec<&ChatCompletionTool> = match &request.tool_choice {
+        Some(ChatCompletionToolChoice::None) => return Ok(None),
+        Some(ChatCompletionToolChoice::ChatCompletionToolChoiceFunction(choice)) => {
+            let name = &choice.function.name;
+            let tool = tools
+                .iter()
+                .find(|tool| tool.function.name == *name)
+                .ok_or_else(|| {
+                    AiRouterError::BadRequestError(format!(
+                        "tool_choice function {name} is not in tools"
+                    ))
+                })?;
+            vec![tool]
+        }
+        Some(ChatCompletionToolChoice::Required) if tools.is_empty() => {
+            return Err(AiRouterError::BadRequestError(String::from(
+                "tool_choice is required, but there are no tools",
+            )));
+        }
+        _ => tools.iter().collect(),
+    };
+    if tools.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(
+        tools
+            .into_iter()
+            .map(serde_json::to_value)
+            .collect::<Result<_, _>>()?,
+    ))
+}
+
+/// Format the messages using the prompt template of the model, models without `prompt_format`
+/// get a plain `Role: content` transcript
+///
+/// `instructions` are appended to the system prompt.
+///
+/// # Errors
+/// `AiRouterError::BadRequestError` when `tools` are set but the model has no prompt template
+fn build_chat_history(
+    messages: Vec<ChatMessage>,
+    tools: Option<&[Value]>,
+    instructions: Option<&str>,
+    template: Option<&PromptTemplate>,
+) -> Result<String, AiRouterError<String>> {
+    let mut messages: Vec<PromptMessage> = messages
+        .iter()
+        .map(prompt_message)
+        .collect::<Result<Vec<Option<PromptMessage>>, _>>()?
+        .into_iter()
+        .flatten()
+        .collect();
+
+    if let Some(instructions) = instructions {
+        match messages.first_mut() {
+            Some(message) if message.role == "system" => {
+                message.content = format!("{}\n\n{instructions}", message.content);
+            }
+            _ => messages.insert(
+                0,
+                PromptMessage {
+                    role: "system",
+                    content: String::from(instructions),
+                    name: None,
+                    tool_calls: None,
+                    tool_call_id: None,
+                },
+            ),
+        }
+    }
+
+    if let Some(template) = template {
+        return template.render(&messages, tools);
+    }
+    if tools.is_some() {
+        return Err(AiRouterError::BadRequestError(String::from(
+            "tools are only supported for models with a prompt_format",
+        )));
+    }
+
     let mut history = String::new();
     for message in messages {
-        let role = match &message {
-            ChatMessage::Developer { .. } => "System",
-            ChatMessage::System { .. } => "System",
-            ChatMessage::User { .. } => "User",
-            ChatMessage::Assistant { .. } => "Assistant",
-            ChatMessage::Tool { .. } => "Tool",
-        };
-        let Some(content) = message.text() else {
+        // only prompt templates render tool calls, messages with nothing else are left out
+        if message.content.is_empty() && message.tool_calls.is_some() {
             continue;
+        }
+        let role = match message.role {
+            "system" => "System",
+            "user" => "User",
+            "assistant" => "Assistant",
+            _ => "Tool",
         };
-        if let Some(name) = message.name() {
-            history.push_str(&format!("{role} {name}: {content}\n"));
+        if let Some(name) = message.name {
+            history.push_str(&format!("{role} {name}: {}\n", message.content));
         } else {
-            history.push_str(&format!("{role}: {content}\n"));
+            history.push_str(&format!("{role}: {}\n", message.content));
         }
     }
     history.push_str("ASSISTANT:");
-    history
+    Ok(history)
+}
+
+/// Convert a message for the prompt template, `None` for messages without text or tool calls
+fn prompt_message(message: &ChatMessage) -> Result<Option<PromptMessage>, AiRouterError<String>> {
+    let role = match message {
+        ChatMessage::Developer { .. } | ChatMessage::System { .. } => "system",
+        ChatMessage::User { .. } => "user",
+        ChatMessage::Assistant { .. } => "assistant",
+        ChatMessage::Tool { .. } => "tool",
+    };
+
+    let value = serde_json::to_value(message)?;
+    let tool_calls = value["tool_calls"].as_array().map(|tool_calls| {
+        tool_calls
+            .iter()
+            .cloned()
+            .map(|mut tool_call| {
+                // templates expect the arguments as object
+                if let Some(arguments) = tool_call["function"]["arguments"].as_str() {
+                    if let Ok(arguments) = serde_json::from_str::<Value>(arguments) {
+                        tool_call["function"]["arguments"] = arguments;
+                    }
+                }
+                tool_call
+            })
+            .collect::<Vec<Value>>()
+    });
+
+    let content = message.text().map(|content| content.to_string());
+    if content.is_none() && tool_calls.is_none() {
+        return Ok(None);
+    }
+
+    Ok(Some(PromptMessage {
+        role,
+        content: content.unwrap_or_default(),
+        name: message.name().map(|name| name.to_string()),
+        tool_calls,
+        tool_call_id: value["tool_call_id"].as_str().map(String::from),
+    }))
+}
+
+fn to_tool_call(tool_call: ParsedToolCall) -> ToolCall {
+    ToolCall {
+        id: format!("call_{}", Uuid::new_v4().simple()),
+        r#type: String::from("function"),
+        function: Function {
+            name: tool_call.name,
+            arguments: tool_call.arguments,
+        },
+    }
+}
+
+fn content_delta(content: String) -> DeltaChatMessage {
+    DeltaChatMessage::Assistant {
+        content: Some(ChatMessageContent::Text(content)),
+        reasoning: None,
+        reasoning_content: None,
+        refusal: None,
+        name: None,
+        tool_calls: None,
+    }
+}
+
+fn delta_empty() -> DeltaChatMessage {
+    DeltaChatMessage::Untagged {
+        content: None,
+        reasoning: None,
+        reasoning_content: None,
+        refusal: None,
+        name: None,
+        tool_calls: None,
+        tool_call_id: None,
+    }
+}
+
+fn tool_calls_delta(tool_calls: Vec<ParsedToolCall>) -> DeltaChatMessage {
+    let tool_calls = tool_calls
+        .into_iter()
+        .map(to_tool_call)
+        .zip(0..)
+        .map(|(tool_call, index)| DeltaToolCall {
+            index: Some(index),
+            id: Some(tool_call.id),
+            r#type: Some(tool_call.r#type),
+            function: DeltaFunction {
+                name: Some(tool_call.function.name),
+                arguments: Some(tool_call.function.arguments),
+            },
+        })
+        .collect();
+
+    DeltaChatMessage::Assistant {
+        content: None,
+        reasoning: None,
+        reasoning_content: None,
+        refusal: None,
+        name: None,
+        tool_calls: Some(tool_calls),
+    }
+}
+
+fn chunk_response(
+    id: &str,
+    created: u32,
+    model: &str,
+    index: u32,
+    delta: DeltaChatMessage,
+    finish_reason: Option<FinishReason>,
+) -> ChatCompletionChunkResponse {
+    ChatCompletionChunkResponse {
+        id: Some(String::from(id)),
+        object: String::from("chat.completion.chunk"),
+        created,
+        model: String::from(model),
+        system_fingerprint: None,
+        usage: None,
+        choices: vec![ChatCompletionChunkChoice {
+            index: Some(index),
+            delta,
+            finish_reason,
+            logprobs: None,
+        }],
+    }
 }
 
 fn string_vec_to_byte_vecs(strings: &Vec<String>) -> Vec<Vec<u8>> {
@@ -383,6 +989,8 @@ fn string_vec_to_byte_vecs(strings: &Vec<String>) -> Vec<Vec<u8>> {
 
 #[cfg(test)]
 mod tests {
+    use std::str::FromStr;
+
     use super::*;
 
     #[test]
@@ -401,8 +1009,73 @@ mod tests {
         }];
 
         assert_eq!(
-            build_chat_history(messages),
+            build_chat_history(messages, None, None, None).expect("failed to build chat history"),
             "System policy: Follow the developer instructions\nASSISTANT:"
         );
     }
+
+    #[test]
+    fn assistant_tool_calls_without_content_are_left_out_of_default_chat_history() {
+        let messages = vec![
+            ChatMessage::User {
+                content: ChatMessageContent::Text("What is the weather in Paris?".to_string()),
+                name: None,
+            },
+            ChatMessage::Assistant {
+                content: None,
+                reasoning: None,
+                reasoning_content: None,
+                refusal: None,
+                name: None,
+                audio: None,
+                tool_calls: Some(vec![ToolCall {
+                    id: "call_1".to_string(),
+                    r#type: "function".to_string(),
+                    function: Function {
+                        name: "get_weather".to_string(),
+                        arguments: r#"{"city": "Paris"}"#.to_string(),
+                    },
+                }]),
+            },
+        ];
+
+        assert_eq!(
+            build_chat_history(messages, None, None, None).expect("failed to build chat history"),
+            "User: What is the weather in Paris?\nASSISTANT:"
+        );
+    }
+
+    #[test]
+    fn streamed_cumulative_outputs_only_add_logprobs_of_new_tokens() {
+        let tokenizer = std::fs::read_to_string("tests/backend.triton.logprobs.tokenizer")
+            .expect("failed to read tokenizer");
+        let options = OutputOptions {
+            max_tokens: MAX_TOKENS,
+            tool_names: None,
+            require_tool_call: false,
+            response_format: None,
+            stop: Vec::new(),
+            logprobs: Some(Arc::new(
+                Tokenizer::from_str(&tokenizer).expect("failed to load tokenizer"),
+            )),
+            top_logprobs: false,
+        };
+        let mut choice = StreamedChoice::new(false, AiRouterStreamOutput::Cumulative);
+
+        let responses = [
+            ("Paris", vec![1], vec![-0.5], "Paris"),
+            ("Paris is", vec![1, 2], vec![-0.5, -1.0], " is"),
+        ];
+        for (output, ids, logprobs, token) in responses {
+            let tokens = OutputTokens { ids, logprobs };
+            let (_, logprobs) = choice
+                .push(output.as_bytes(), tokens, Some(2), &options)
+                .expect("failed to push output")
+                .expect("no delta");
+            let content = &logprobs.expect("no logprobs")["content"];
+            assert_eq!(content.as_array().map(Vec::len), Some(1));
+            assert_eq!(content[0]["token"], token);
+        }
+        assert_eq!(choice.output_ids, [1, 2]);
+    }
 }
@@ -11,19 +11,30 @@ use axum::Json;
 use openai_dive::v1::resources::shared::{FinishReason, Usage};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tonic::codegen::tokio_stream::Stream;
+use serde_with::skip_serializing_none;
+use tokenizers::Tokenizer;
+use tonic::codegen::tokio_stream::{Stream, StreamExt};
 use tonic::transport::Channel;
 use tracing;
 use tracing::instrument;
 use uuid::Uuid;
 
 use crate::backend::triton::grpc_inference_service_client::GrpcInferenceServiceClient;
+use crate::backend::triton::logprobs::{
+    completion_logprobs, new_tokens, output_tokens, token_logprobs, CompletionLogprobs,
+    OutputTokens, OUTPUT_IDS_OUTPUT, OUTPUT_LOG_PROBS_OUTPUT, RETURN_LOG_PROBS_INPUT,
+};
 use crate::backend::triton::request::{Builder, InferTensorData};
-use crate::backend::triton::utils::get_output_idx;
+use crate::backend::triton::stream_decoder::StreamDecoder;
+use crate::backend::triton::utils::{
+    add_sequence_length, check_n, count_completion_tokens, finish_reason, get_output_idx,
+    output_sequence_lengths, sample_requests, stream_infer, SEQUENCE_LENGTH_OUTPUT,
+};
 use crate::backend::triton::ModelInferRequest;
-use crate::errors::AiRouterError;
+use crate::config::AiRouterStreamOutput;
+use crate::errors::{transform_triton_status, AiRouterError};
 use crate::request::{check_input_cc, AiRouterRequestData};
-use crate::utils::{deserialize_bytes_tensor, string_or_seq_string};
+use crate::utils::{deserialize_bytes_tensor, split_bytes_tensor};
 
 const MAX_TOKENS: u32 = 131_072;
 const MODEL_OUTPUT_NAME: &str = "text_output";
@@ -49,31 +60,73 @@ pub async fn compat_completions(
 
 #[instrument(skip(client, request, request_data))]
 async fn completions_stream(
-    mut client: GrpcInferenceServiceClient<Channel>,
+    client: GrpcInferenceServiceClient<Channel>,
     Json(request): Json<CompletionCreateParams>,
     request_data: &mut AiRouterRequestData,
 ) -> Result<Sse<impl Stream<Item = anyhow::Result<Event>>>, AiRouterError<String>> {
     let id = format!("cmpl-{}", Uuid::new_v4());
     let created = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
 
-    let request = build_triton_request(request, request_data)?;
+    let include_usage = request
+        .stream_options
+        .as_ref()
+        .is_some_and(|stream_options| stream_options.include_usage);
+    let (max_tokens, stop) = resolve_limits(&request, request_data);
+    let logprobs = request.logprobs.is_some();
+    let requests = prepare_requests(request, request_data)?;
     let model_name = request_data
         .original_model
         .clone()
-        .unwrap_or(request.model_name.clone());
+        .unwrap_or(requests[0].model_name.clone());
+    let request_data = request_data.clone();
+    let tokenizer = request_data.tokenizer.clone().filter(|_| logprobs);
+
+    let mut decoders: Vec<StreamDecoder> = requests
+        .iter()
+        .map(|_| StreamDecoder::new(request_data.stream_output))
+        .collect();
+    let mut outputs = vec![String::new(); requests.len()];
+    // tokens of the output that was not sent yet, and ids of the tokens sent before
+    let mut pending_tokens = vec![OutputTokens::default(); requests.len()];
+    let mut output_ids: Vec<Vec<u32>> = vec![Vec::new(); requests.len()];
+    let mut sequence_lengths: Vec<Option<u32>> = vec![Some(0); requests.len()];
+    let mut stream = stream_infer(&client, requests).await?;
 
     let response_stream = try_stream! {
-        let request_stream = stream! { yield request };
-
-        let mut stream = client
-            .model_stream_infer(tonic::Request::new(request_stream))
-            .await
-            .context("failed to call triton grpc method model_stream_infer")?
-            .into_inner();
-
-        let mut content_prev = String::new();
+        let mut completion_tokens = 0;
+
+        while let Some((index, response)) = stream.next().await {
+            let Some(response) = response else {
+                let text = decoders[index].finish();
+                let logprobs = choice_logprobs(
+                    tokenizer.as_deref(),
+                    &mut output_ids[index],
+                    std::mem::take(&mut pending_tokens[index]),
+                    outputs[index].chars().count(),
+                )?;
+                outputs[index].push_str(&text);
+                let output = &outputs[index];
+                let choice_tokens =
+                    count_completion_tokens(sequence_lengths[index], output, &request_data);
+                completion_tokens += choice_tokens;
+                let response = Completion {
+                    id: id.clone(),
+                    object: "text_completion".to_string(),
+                    created,
+                    model: model_name.clone(),
+                    choices: vec![CompletionChoice {
+                        text,
+                        index,
+                        logprobs,
+                        finish_reason: Some(finish_reason(output, &stop, choice_tokens, max_tokens)),
+                    }],
+                    usage: None,
+                };
+                yield Event::default().json_data(response)?;
+                continue;
+            };
 
-        while let Some(response) = stream.message().await? {
+            let response = response?;
             if !response.error_message.is_empty() {
                 tracing::error!("received error message from triton: {}", response.error_message);
 
@@ -101,17 +154,33 @@ async fn completions_stream(
             };
 
             let raw_content = infer_response.raw_output_contents[idx].clone();
-            let content = deserialize_bytes_tensor(raw_content)?
-                .into_iter()
-                .map(|s| s.replace("</s>", ""))
-                .collect::<String>();
-
-            if !content.is_empty() {
-                let content_new = content.replace(&content_prev, "");
-                if content_new.is_empty() {
-                    continue;
-                }
-                content_prev.clone_from(&content);
+            let output = split_bytes_tensor(raw_content).concat();
+            if tokenizer.is_some() {
+                let tokens = output_tokens(&infer_response)?.into_iter().next();
+                let received = output_ids[index].len() + pending_tokens[index].ids.len();
+                pending_tokens[index].extend(new_tokens(
+                    tokens.unwrap_or_default(),
+                    received,
+                    request_data.stream_output,
+                ));
+            }
+            let sequence_length = output_sequence_lengths(&infer_response)
+                .and_then(|lengths| lengths.first().copied());
+            sequence_lengths[index] = add_sequence_length(
+                sequence_lengths[index],
+                sequence_length,
+                request_data.stream_output,
+            );
+
+            let content_new = decoders[index].push(&output);
+            if !content_new.is_empty() {
+                let logprobs = choice_logprobs(
+                    tokenizer.as_deref(),
+                    &mut output_ids[index],
+                    std::mem::take(&mut pending_tokens[index]),
+                    outputs[index].chars().count(),
+                )?;
+                outputs[index].push_str(&content_new);
                 let response = Completion {
                     id: id.clone(),
                     object: "text_completion".to_string(),
@@ -119,8 +188,8 @@ async fn completions_stream(
                     model: model_name.clone(),
                     choices: vec![CompletionChoice {
                         text: content_new,
-                        index: 0,
-                        logprobs: None,
+                        index,
+                        logprobs,
                         finish_reason: None,
                     }],
                     usage: None,
@@ -128,20 +197,18 @@ async fn completions_stream(
                 yield Event::default().json_data(response)?;
             }
         }
-        let response = Completion {
-            id,
-            object: "text_completion".to_string(),
-            created,
-            model: model_name,
-            choices: vec![CompletionChoice {
-                text: String::new(),
-                index: 0,
-                logprobs: None,
-                finish_reason: Some(FinishReason::StopSequenceReached),
-            }],
-            usage: None,
-        };
-        yield Event::default().json_data(response)?;
+
+        if include_usage {
+            let response = Completion {
+                id,
+                object: "text_completion".to_string(),
+                created,
+                model: model_name,
+                choices: Vec::new(),
+                usage: Some(request_data.usage(completion_tokens)),
+            };
+            yield Event::default().json_data(response)?;
+        }
 
         // OpenAI stream response terminated by a data: [DONE] message.
         yield Event::default().data("[DONE]");
@@ -152,26 +219,78 @@ async fn completions_stream(
 
 #[instrument(skip(client, request, request_data), err(Debug))]
 async fn completions(
-    mut client: GrpcInferenceServiceClient<Channel>,
+    client: GrpcInferenceServiceClient<Channel>,
     Json(request): Json<CompletionCreateParams>,
     request_data: &mut AiRouterRequestData,
 ) -> Result<Json<Completion>, AiRouterError<String>> {
-    let request = build_triton_request(request, request_data)?;
+    let n = request.n();
+    let (max_tokens, stop) = resolve_limits(&request, request_data);
+    let logprobs = request.logprobs.is_some();
+    let requests = prepare_requests(request, request_data)?;
     let model_name = request_data
         .original_model
         .clone()
-        .unwrap_or(request.model_name.clone());
+        .unwrap_or(requests[0].model_name.clone());
+    let tokenizer = request_data.tokenizer.clone().filter(|_| logprobs);
+
+    // beam search returns all beams in one response, sampling one output per request
+    let outputs = futures::future::try_join_all(
+        requests
+            .into_iter()
+            .map(|request| infer(client.clone(), request, logprobs)),
+    )
+    .await?;
+    let mut completion_tokens = 0;
+    let choices = outputs
+        .into_iter()
+        .flatten()
+        .take(n)
+        .enumerate()
+        .map(|(index, (text, tokens, sequence_length))| {
+            let choice_tokens = count_completion_tokens(sequence_length, &text, request_data);
+            completion_tokens += choice_tokens;
+            Ok(CompletionChoice {
+                finish_reason: Some(finish_reason(&text, &stop, choice_tokens, max_tokens)),
+                logprobs: choice_logprobs(tokenizer.as_deref(), &mut Vec::new(), tokens, 0)?,
+                text,
+                index,
+            })
+        })
+        .collect::<Result<_, AiRouterError<String>>>()?;
+
+    Ok(Json(Completion {
+        id: format!("cmpl-{}", Uuid::new_v4()),
+        object: "text_completion".to_string(),
+        created: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        model: model_name,
+        choices,
+        usage: Some(request_data.usage(completion_tokens)),
+    }))
+}
+
+/// Send a request to Triton and collect the complete output of each beam, with its tokens and
+/// their log probabilities when `logprobs` is set and its number of tokens when Triton returns a
+/// `sequence_length`
+async fn infer(
+    mut client: GrpcInferenceServiceClient<Channel>,
+    request: ModelInferRequest,
+    logprobs: bool,
+) -> Result<Vec<(String, OutputTokens, Option<u32>)>, AiRouterError<String>> {
     let request_stream = stream! { yield request };
     let mut stream = client
         .model_stream_infer(tonic::Request::new(request_stream))
         .await
-        .context("failed to call triton grpc method model_stream_infer")?
+        .map_err(|e| transform_triton_status(&e))?
         .into_inner();
 
-    let mut contents: Vec<String> = Vec::new();
-    while let Some(response) = stream.message().await? {
+    let mut outputs: Vec<(String, OutputTokens, Option<u32>)> = Vec::new();
+    while let Some(response) = stream
+        .message()
+        .await
+        .map_err(|e| transform_triton_status(&e))?
+    {
         if !response.error_message.is_empty() {
-            return Err(AiRouterError::InternalServerError(format!(
+            return Err(AiRouterError::BackendError(format!(
                 "error message received from triton: {}",
                 response.error_message
             )));
@@ -188,45 +307,146 @@ async fn completions(
         };
 
         let raw_content = infer_response.raw_output_contents[idx].clone();
-        let content = deserialize_bytes_tensor(raw_content)?
-            .into_iter()
-            .map(|s| s.trim().replace("</s>", ""))
-            .collect();
-        contents.push(content);
+        let beams = deserialize_bytes_tensor(raw_content)?;
+        let mut beams_tokens = if logprobs {
+            output_tokens(&infer_response)?
+        } else {
+            Vec::new()
+        };
+        beams_tokens.resize(beams.len(), OutputTokens::default());
+        let sequence_lengths = output_sequence_lengths(&infer_response);
+        if outputs.len() < beams.len() {
+            outputs.resize(
+                beams.len(),
+                (String::new(), OutputTokens::default(), Some(0)),
+            );
+        }
+        for (i, ((output, tokens, sequence_length), (beam, beam_tokens))) in outputs
+            .iter_mut()
+            .zip(beams.into_iter().zip(beams_tokens))
+            .enumerate()
+        {
+            output.push_str(beam.trim());
+            tokens.extend(beam_tokens);
+            *sequence_length = add_sequence_length(
+                *sequence_length,
+                sequence_lengths
+                    .as_ref()
+                    .and_then(|lengths| lengths.get(i).copied()),
+                AiRouterStreamOutput::Delta,
+            );
+        }
     }
 
-    let prompt_tokens = request_data.prompt_tokens.try_into().unwrap_or(0);
+    // on the complete outputs, as the end of sequence token can be split across responses
+    for (output, _, _) in &mut outputs {
+        *output = output.replace("</s>", "");
+    }
 
-    Ok(Json(Completion {
-        id: format!("cmpl-{}", Uuid::new_v4()),
-        object: "text_completion".to_string(),
-        created: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
-        model: model_name,
-        choices: vec![CompletionChoice {
-            text: contents.into_iter().collect(),
-            index: 0,
-            logprobs: None,
-            finish_reason: Some(FinishReason::StopSequenceReached),
-        }],
-        // Not fully supported yet, need Triton to return usage stats
-        // but populate prompt_tokens and total_tokens for models configured with max_tokens
-        usage: Some(Usage {
-            prompt_tokens: Some(prompt_tokens),
-            completion_tokens: Some(0),
-            // add completion_tokens once we can get them from Triton
-            total_tokens: prompt_tokens,
-            prompt_tokens_details: None,
-            completion_tokens_details: None,
-        }),
-    }))
+    Ok(outputs)
+}
+
+/// `logprobs` of the `tokens` of a choice with `text_offset` counting characters from `offset`,
+/// `None` without a tokenizer
+///
+/// The ids of the `tokens` are added to `output_ids`, the ids of the tokens generated before.
+///
+/// # Errors
+/// - when the tokenizer fails to decode the tokens
+fn choice_logprobs(
+    tokenizer: Option<&Tokenizer>,
+    output_ids: &mut Vec<u32>,
+    tokens: OutputTokens,
+    offset: usize,
+) -> anyhow::Result<Option<CompletionLogprobs>> {
+    let Some(tokenizer) = tokenizer else {
+        return Ok(None);
+    };
+    let token_logprobs = token_logprobs(tokenizer, output_ids, &tokens)?;
+    output_ids.extend(tokens.ids);
+
+    Ok(Some(completion_logprobs(&token_logprobs, offset)))
+}
+
+/// Build the Triton requests for the `n` choices of a request, a single beam search request when
+/// `best_of` is greater than 1, or a sampling request for each choice otherwise
+///
+/// # Errors
+/// `AiRouterError::BadRequestError` when `n` is invalid, see `check_n`, `best_of` is less than `n`
+/// or more than `max_n`, `best_of` is set for a streaming request, or `logprobs` are more than 1 or
+/// requested for a model without tokenizer
+fn prepare_requests(
+    request: CompletionCreateParams,
+    request_data: &mut AiRouterRequestData,
+) -> Result<Vec<ModelInferRequest>, AiRouterError<String>> {
+    let best_of = request.best_of();
+    let beam_search = best_of > 1;
+    let n = check_n(
+        request.n(),
+        request_data.max_n,
+        request.temperature,
+        beam_search,
+    )?;
+    if best_of > usize::try_from(request_data.max_n)? {
+        return Err(AiRouterError::BadRequestError(format!(
+            "best_of must be at most {}",
+            request_data.max_n
+        )));
+    }
+    if beam_search && best_of < usize::try_from(n)? {
+        return Err(AiRouterError::BadRequestError(String::from(
+            "best_of must be greater than or equal to n",
+        )));
+    }
+    if best_of > 1 && request.stream {
+        return Err(AiRouterError::BadRequestError(String::from(
+            "best_of is not supported for streaming requests",
+        )));
+    }
+    if request.logprobs.is_some_and(|logprobs| logprobs > 1) {
+        return Err(AiRouterError::BadRequestError(String::from(
+            "logprobs greater than 1 is not supported, Triton only returns the generated token",
+        )));
+    }
+    if request.logprobs.is_some() && request_data.tokenizer.is_none() {
+        return Err(AiRouterError::BadRequestError(format!(
+            "logprobs are not supported for model {}, as it has no hf_model_name",
+            request.model
+        )));
+    }
+
+    let request = build_triton_request(request, request_data)?;
+
+    if beam_search {
+        Ok(vec![request])
+    } else {
+        Ok(sample_requests(request, n))
+    }
+}
+
+/// Maximum number of tokens and stop sequences of a request
+fn resolve_limits(
+    request: &CompletionCreateParams,
+    request_data: &AiRouterRequestData,
+) -> (u32, Vec<String>) {
+    let max_tokens = request
+        .max_tokens
+        .unwrap_or(request_data.max_tokens.unwrap_or(MAX_TOKENS));
+    let stop = request
+        .stop
+        .clone()
+        .unwrap_or_else(|| vec!["</s>".to_string()]);
+
+    (max_tokens, stop)
 }
 
 fn build_triton_request(
     request: CompletionCreateParams,
     request_data: &mut AiRouterRequestData,
 ) -> Result<ModelInferRequest, AiRouterError<String>> {
-    let input: String = request.prompt.join(" ");
+    let input: String = request.prompt.texts().join(" ");
     check_input_cc(&input, &request.model, request_data)?;
+    let (max_tokens, stop) = resolve_limits(&request, request_data);
 
     let mut builder = Builder::new()
         .model_name(request.model)
@@ -236,7 +456,8 @@ fn build_triton_request(
             InferTensorData::Bytes(
                 request
                     .prompt
-                    .into_iter()
+                    .texts()
+                    .iter()
                     .map(|s| s.as_bytes().to_vec())
                     .collect(),
             ),
@@ -244,11 +465,7 @@ fn build_triton_request(
         .input(
             "max_tokens",
             [1, 1],
-            InferTensorData::Int32(vec![i32::try_from(
-                request
-                    .max_tokens
-                    .unwrap_or(request_data.max_tokens.unwrap_or(MAX_TOKENS)),
-            )?]),
+            InferTensorData::Int32(vec![i32::try_from(max_tokens)?]),
         )
         .input(
             "bad_words",
@@ -259,36 +476,38 @@ fn build_triton_request(
             "stop_words",
             [1, 1],
             InferTensorData::Bytes(
-                request
-                    .stop
-                    .unwrap_or_else(|| vec!["</s>".to_string()])
-                    .into_iter()
+                stop.into_iter()
                     .map(std::string::String::into_bytes)
                     .collect(),
             ),
         )
-        .input("top_p", [1, 1], InferTensorData::FP32(vec![request.top_p]))
+        .input(
+            "top_p",
+            [1, 1],
+            InferTensorData::FP32(vec![request.top_p.unwrap_or(1.0)]),
+        )
         .input(
             "temperature",
             [1, 1],
-            InferTensorData::FP32(vec![request.temperature]),
+            InferTensorData::FP32(vec![request.temperature.unwrap_or(1.0)]),
         )
         .input(
             "presence_penalty",
             [1, 1],
-            InferTensorData::FP32(vec![request.presence_penalty]),
+            InferTensorData::FP32(vec![request.presence_penalty.unwrap_or(0.0)]),
         )
         .input(
             "beam_width",
             [1, 1],
-            InferTensorData::Int32(vec![i32::try_from(request.n)?]),
+            InferTensorData::Int32(vec![i32::try_from(request.best_of.unwrap_or(1))?]),
         )
         .input(
             "stream",
             [1, 1],
             InferTensorData::Bool(vec![request.stream]),
         )
-        .output(MODEL_OUTPUT_NAME);
+        .output(MODEL_OUTPUT_NAME)
+        .output(SEQUENCE_LENGTH_OUTPUT);
 
     if let Some(seed) = request.seed {
         builder = builder.input(
@@ -298,30 +517,38 @@ fn build_triton_request(
         );
     }
 
+    if request.logprobs.is_some() {
+        builder = builder
+            .input(
+                RETURN_LOG_PROBS_INPUT,
+                [1, 1],
+                InferTensorData::Bool(vec![true]),
+            )
+            .output(OUTPUT_LOG_PROBS_OUTPUT)
+            .output(OUTPUT_IDS_OUTPUT);
+    }
+
     Ok(builder.build().context("failed to build triton request")?)
 }
 
 #[allow(dead_code)]
-#[derive(Deserialize, Debug)]
+#[skip_serializing_none]
+#[derive(Clone, Deserialize, Debug, Serialize)]
 pub struct CompletionCreateParams {
     /// ID of the model to use.
     pub model: String,
     /// The prompt(s) to generate completions for, encoded as a string, array of strings, array of
     /// tokens, or array of token arrays.
-    #[serde(deserialize_with = "string_or_seq_string")]
-    prompt: Vec<String>,
+    prompt: Prompt,
     /// Generates best_of completions server-side and returns the "best" (the one with the highest
     /// log probability per token). Results cannot be streamed.
-    #[serde(default = "default_best_of")]
-    best_of: usize,
+    best_of: Option<usize>,
     /// Echo back the prompt in addition to the completion
-    #[serde(default = "default_echo")]
-    echo: bool,
+    echo: Option<bool>,
     /// Number between -2.0 and 2.0. Positive values penalize new tokens based on their existing
     /// frequency in the text so far, decreasing the model's likelihood to repeat the same line
     /// verbatim.
-    #[serde(default = "default_frequency_penalty")]
-    frequency_penalty: f32,
+    frequency_penalty: Option<f32>,
     /// Modify the likelihood of specified tokens appearing in the completion.
     logit_bias: Option<HashMap<String, f32>>,
     /// Include the log probabilities on the logprobs most likely tokens, as well the chosen tokens.
@@ -330,12 +557,10 @@ pub struct CompletionCreateParams {
     #[serde(skip_serializing_if = "Option::is_none")]
     max_tokens: Option<u32>,
     /// How many completions to generate for each prompt.
-    #[serde(default = "default_n")]
-    n: usize,
+    n: Option<usize>,
     /// Number between -2.0 and 2.0. Positive values penalize new tokens based on whether they
     /// appear in the text so far, increasing the model's likelihood to talk about new topics.
-    #[serde(default = "default_presence_penalty")]
-    presence_penalty: f32,
+    presence_penalty: Option<f32>,
     /// If specified, our system will make a best effort to sample deterministically, such that
     /// repeated requests with the same seed and parameters should return the same result.
     seed: Option<usize>,
@@ -344,23 +569,61 @@ pub struct CompletionCreateParams {
     stop: Option<Vec<String>>,
     /// Whether to stream back partial progress.
     #[serde(default = "default_stream")]
-    stream: bool,
+    pub stream: bool,
+    /// Options for streaming response. Only set this when you set `stream: true`.
+    stream_options: Option<StreamOptions>,
     /// The suffix that comes after a completion of inserted text.
     suffix: Option<String>,
     /// What sampling temperature to use, between 0 and 2. Higher values like 0.8 will make the
     /// output more random, while lower values like 0.2 will make it more focused and deterministic.
-    #[serde(default = "default_temperature")]
-    temperature: f32,
+    temperature: Option<f32>,
     /// An alternative to sampling with temperature, called nucleus sampling, where the model
     /// considers the results of the tokens with top_p probability mass. So 0.1 means only the
     /// tokens comprising the top 10% probability mass are considered.
-    #[serde(default = "default_top_p")]
-    top_p: f32,
+    top_p: Option<f32>,
     /// A unique identifier representing your end-user, which can help OpenAI to monitor and detect
     /// abuse.
     user: Option<String>,
 }
 
+impl CompletionCreateParams {
+    /// Number of completions to return, 1 if unset
+    fn n(&self) -> usize {
+        self.n.unwrap_or(1)
+    }
+
+    /// Number of completions to generate server-side, 1 if unset
+    fn best_of(&self) -> usize {
+        self.best_of.unwrap_or(1)
+    }
+}
+
+/// Prompt of a completion request, forwarded to `OpenAI` backends in the form it was received
+#[derive(Clone, Deserialize, Debug, Serialize)]
+#[serde(untagged)]
+enum Prompt {
+    String(String),
+    Array(Vec<String>),
+}
+
+impl Prompt {
+    fn texts(&self) -> &[String] {
+        match self {
+            Self::String(prompt) => std::slice::from_ref(prompt),
+            Self::Array(prompts) => prompts,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Debug, Serialize)]
+struct StreamOptions {
+    /// If set, an additional chunk will be streamed before the data: [DONE] message. The usage
+    /// field on this chunk shows the token usage statistics for the entire request, and the
+    /// choices field will always be an empty array.
+    #[serde(default)]
+    include_usage: bool,
+}
+
 #[derive(Serialize, Debug)]
 struct Completion {
     /// A unique identifier for the completion.
@@ -381,38 +644,41 @@ struct Completion {
 struct CompletionChoice {
     text: String,
     index: usize,
-    logprobs: Option<()>,
+    logprobs: Option<CompletionLogprobs>,
     finish_reason: Option<FinishReason>,
 }
 
-fn default_best_of() -> usize {
-    1
-}
-
-fn default_echo() -> bool {
-    false
-}
-
-fn default_frequency_penalty() -> f32 {
-    0.0
-}
-
-fn default_n() -> usize {
-    1
-}
-
-fn default_presence_penalty() -> f32 {
-    0.0
-}
-
 fn default_stream() -> bool {
     false
 }
 
-fn default_temperature() -> f32 {
-    1.0
-}
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    #[test]
+    fn test_serialize_params() {
+        let request = json!({"model": "test", "prompt": "Say this is a test", "max_tokens": 7});
+        let params: CompletionCreateParams =
+            serde_json::from_value(request.clone()).expect("failed to deserialize request");
+        assert_eq!(params.n(), 1);
+        assert_eq!(params.prompt.texts(), ["Say this is a test"]);
+
+        let mut expected = request;
+        expected["stream"] = Value::Bool(false);
+        assert_eq!(
+            serde_json::to_value(&params).expect("failed to serialize request"),
+            expected
+        );
 
-fn default_top_p() -> f32 {
-    1.0
+        let request = json!({"model": "test", "prompt": ["a", "b"], "n": 2, "stream": false});
+        let params: CompletionCreateParams =
+            serde_json::from_value(request.clone()).expect("failed to deserialize request");
+        assert_eq!(
+            serde_json::to_value(&params).expect("failed to serialize request"),
+            request
+        );
+    }
 }
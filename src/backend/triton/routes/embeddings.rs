@@ -13,7 +13,7 @@ use crate::backend::triton::grpc_inference_service_client::GrpcInferenceServiceC
 use crate::backend::triton::request::{Builder, InferTensorData};
 use crate::backend::triton::utils::get_output_idx;
 use crate::backend::triton::ModelInferRequest;
-use crate::errors::AiRouterError;
+use crate::errors::{transform_triton_status, AiRouterError};
 use crate::request::AiRouterRequestData;
 
 const MODEL_OUTPUT_NAME: &str = "embedding";
@@ -31,6 +31,14 @@ pub(crate) async fn embed(
         EmbeddingInput::IntegerArrayArray(ref iaa) => iaa.len(),
         EmbeddingInput::IntegerArray(_) | EmbeddingInput::String(_) => 1,
     };
+    let prompt_tokens = match &request.input {
+        EmbeddingInput::String(s) => request_data.count_tokens(s),
+        EmbeddingInput::StringArray(sa) => sa.iter().map(|s| request_data.count_tokens(s)).sum(),
+        EmbeddingInput::IntegerArray(ia) => u32::try_from(ia.len())?,
+        EmbeddingInput::IntegerArrayArray(iaa) => {
+            u32::try_from(iaa.iter().map(Vec::len).sum::<usize>())?
+        }
+    };
     let mut dimensions: usize = 0;
 
     let request = build_triton_request(request)?;
@@ -42,13 +50,17 @@ pub(crate) async fn embed(
     let mut stream = client
         .model_stream_infer(tonic::Request::new(request_stream))
         .await
-        .context("failed to call triton grpc method model_stream_infer")?
+        .map_err(|e| transform_triton_status(&e))?
         .into_inner();
 
     let mut data: Vec<u8> = Vec::new();
-    while let Some(response) = stream.message().await? {
+    while let Some(response) = stream
+        .message()
+        .await
+        .map_err(|e| transform_triton_status(&e))?
+    {
         if !response.error_message.is_empty() {
-            return Err(AiRouterError::InternalServerError(format!(
+            return Err(AiRouterError::BackendError(format!(
                 "error message received from triton: {}",
                 response.error_message
             )));
@@ -81,12 +93,10 @@ pub(crate) async fn embed(
         object: String::from("embedding"),
         data: build_embedding_response_data(&data)?,
         model: model_name,
-        // Not supported yet, need triton to return usage stats
-        // but add a fake one to make `openai_dive` `EmbeddingResponse` happy
         usage: Some(Usage {
-            prompt_tokens: Some(0),
+            prompt_tokens: Some(prompt_tokens),
             completion_tokens: Some(0),
-            total_tokens: 0,
+            total_tokens: prompt_tokens,
             prompt_tokens_details: None,
             completion_tokens_details: None,
         }),
@@ -0,0 +1,198 @@
+//! Incremental decoding of streamed Triton outputs
+//!
+//! Depending on the model, every streamed response contains either the complete output generated
+//! so far or only the new part of it, see `AiRouterStreamOutput`. A response can end in the middle
+//! of a multi-byte UTF-8 character, so the outputs are decoded as bytes and incomplete characters
+//! are held back until the rest of them arrives.
+//!
+//! Some models output their `</s>` end of sequence token, which is removed from the text. It can
+//! be split across responses as well, so an output ending with the start of it is held back too.
+use crate::config::AiRouterStreamOutput;
+
+/// UTF-8 encoding of U+FFFD, emitted by detokenizers for incomplete characters
+const REPLACEMENT_CHARACTER: &[u8] = "\u{FFFD}".as_bytes();
+/// End of sequence token removed from the output
+const EOS_TOKEN: &str = "</s>";
+
+/// Turns the outputs of the streamed responses of a request into the text not sent yet
+#[derive(Debug)]
+pub struct StreamDecoder {
+    mode: AiRouterStreamOutput,
+    /// Output received so far, without the text already decoded in `Delta` mode
+    buffer: Vec<u8>,
+    /// Number of bytes of `buffer` already decoded
+    decoded: usize,
+}
+
+impl StreamDecoder {
+    pub const fn new(mode: AiRouterStreamOutput) -> Self {
+        Self {
+            mode,
+            buffer: Vec::new(),
+            decoded: 0,
+        }
+    }
+
+    /// Add the output of a streamed response, returns the text that was not returned before
+    ///
+    /// The returned text is empty when the output adds nothing or only the start of a character.
+    pub fn push(&mut self, output: &[u8]) -> String {
+        match self.mode {
+            AiRouterStreamOutput::Cumulative => {
+                if !output.starts_with(&self.buffer[..self.decoded]) {
+                    // text already sent to the client cannot be taken back
+                    tracing::warn!("cumulative Triton output does not continue previous output");
+                    self.decoded = self.decoded.min(output.len());
+                }
+                self.buffer.clear();
+                self.buffer.extend_from_slice(output);
+            }
+            AiRouterStreamOutput::Delta => {
+                self.buffer.drain(..self.decoded);
+                self.decoded = 0;
+                self.buffer.extend_from_slice(output);
+            }
+        }
+
+        let mut end = self.decoded + complete_len(&self.buffer[self.decoded..]);
+        // a cumulative output may replace incomplete characters with U+FFFD until they are complete
+        if self.mode == AiRouterStreamOutput::Cumulative {
+            while end >= self.decoded + REPLACEMENT_CHARACTER.len()
+                && self.buffer[..end].ends_with(REPLACEMENT_CHARACTER)
+            {
+                end -= REPLACEMENT_CHARACTER.len();
+            }
+        }
+        end -= partial_eos_len(&self.buffer[self.decoded..end]);
+
+        self.decode(end)
+    }
+
+    /// Complete the output after the last response, returns the text that was held back
+    ///
+    /// Incomplete characters at the end of the output are replaced with U+FFFD.
+    pub fn finish(&mut self) -> String {
+        self.decode(self.buffer.len())
+    }
+
+    fn decode(&mut self, end: usize) -> String {
+        let text = String::from_utf8_lossy(&self.buffer[self.decoded..end]).replace(EOS_TOKEN, "");
+        self.decoded = end;
+        text
+    }
+}
+
+/// Length of the start of `EOS_TOKEN` at the end of `bytes`, 0 if it does not end with it
+fn partial_eos_len(bytes: &[u8]) -> usize {
+    (1..EOS_TOKEN.len())
+        .rev()
+        .find(|len| bytes.ends_with(&EOS_TOKEN.as_bytes()[..*len]))
+        .unwrap_or(0)
+}
+
+/// Length of `bytes` without an incomplete UTF-8 character at the end
+///
+/// Invalid bytes in the middle are counted, they are replaced with U+FFFD when decoded.
+fn complete_len(bytes: &[u8]) -> usize {
+    let mut len = 0;
+    loop {
+        match std::str::from_utf8(&bytes[len..]) {
+            Ok(_) => return bytes.len(),
+            Err(e) => match e.error_len() {
+                Some(invalid) => len += e.valid_up_to() + invalid,
+                None => return len + e.valid_up_to(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(mode: AiRouterStreamOutput, outputs: &[&[u8]]) -> Vec<String> {
+        let mut decoder = StreamDecoder::new(mode);
+        let mut texts: Vec<String> = outputs.iter().map(|output| decoder.push(output)).collect();
+        texts.push(decoder.finish());
+        texts
+    }
+
+    #[test]
+    fn test_stream_decoder_cumulative() {
+        assert_eq!(
+            decode(
+                AiRouterStreamOutput::Cumulative,
+                &[b"ha", b"haha", b"haha", b"hahaha!"]
+            ),
+            vec!["ha", "ha", "", "ha!", ""]
+        );
+
+        // "é" is split across responses
+        assert_eq!(
+            decode(
+                AiRouterStreamOutput::Cumulative,
+                &[b"caf", b"caf\xc3", b"caf\xc3\xa9 ok"]
+            ),
+            vec!["caf", "", "é ok", ""]
+        );
+
+        // detokenizer replaces the incomplete "é" with U+FFFD
+        assert_eq!(
+            decode(
+                AiRouterStreamOutput::Cumulative,
+                &["caf\u{FFFD}".as_bytes(), "café".as_bytes()]
+            ),
+            vec!["caf", "é", ""]
+        );
+
+        assert_eq!(
+            decode(AiRouterStreamOutput::Cumulative, &[b"ok", b"ok\xe2\x82"]),
+            vec!["ok", "", "\u{FFFD}"]
+        );
+    }
+
+    #[test]
+    fn test_stream_decoder_delta() {
+        assert_eq!(
+            decode(AiRouterStreamOutput::Delta, &[b"ha", b"ha", b"", b"ha!"]),
+            vec!["ha", "ha", "", "ha!", ""]
+        );
+
+        // "€" is split across three responses
+        assert_eq!(
+            decode(
+                AiRouterStreamOutput::Delta,
+                &[b"1 \xe2", b"\x82", b"\xac", b" each"]
+            ),
+            vec!["1 ", "", "€", " each", ""]
+        );
+
+        assert_eq!(
+            decode(AiRouterStreamOutput::Delta, &[b"a\xffb", b"\xf0\x9f"]),
+            vec!["a\u{FFFD}b", "", "\u{FFFD}"]
+        );
+    }
+
+    #[test]
+    fn test_stream_decoder_eos_token() {
+        assert_eq!(
+            decode(AiRouterStreamOutput::Delta, &[b"done.</", b"s>"]),
+            vec!["done.", "", ""]
+        );
+        assert_eq!(
+            decode(AiRouterStreamOutput::Delta, &[b"a <", b"b", b"</s>"]),
+            vec!["a ", "<b", "", ""]
+        );
+        assert_eq!(
+            decode(AiRouterStreamOutput::Delta, &[b"1 <"]),
+            vec!["1 ", "<"]
+        );
+        assert_eq!(
+            decode(
+                AiRouterStreamOutput::Cumulative,
+                &[b"done", b"done.<", b"done.</s", b"done.</s>"]
+            ),
+            vec!["done", ".", "", "", ""]
+        );
+    }
+}
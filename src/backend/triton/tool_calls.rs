@@ -0,0 +1,194 @@
+//! Tool calls in the output of Triton chat models
+//!
+//! Models call tools with JSON objects containing the `name` of the function and its `arguments`
+//! (or `parameters`), either in `<tool_call>` blocks (Hermes, Qwen) or as the complete output,
+//! optionally prefixed with `[TOOL_CALLS]` (Mistral) or `<|python_tag|>` (Llama 3.1). Only calls of
+//! the functions in the `tools` of the request are accepted, so JSON answers are not mistaken for
+//! tool calls.
+use serde_json::Value;
+
+const TOOL_CALL_START: &str = "<tool_call>";
+const TOOL_CALL_END: &str = "</tool_call>";
+const TOOL_CALL_PREFIXES: [&str; 2] = ["[TOOL_CALLS]", "<|python_tag|>"];
+
+/// Function call parsed from a model output
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParsedToolCall {
+    pub name: String,
+    /// Arguments as JSON string
+    pub arguments: String,
+}
+
+/// Check if a partial model output may still turn out to be tool calls, in which case it must
+/// not be streamed to the client as content yet
+pub fn may_be_tool_call(output: &str) -> bool {
+    let output = output.trim_start();
+
+    [TOOL_CALL_START, "{", "["]
+        .iter()
+        .chain(TOOL_CALL_PREFIXES.iter())
+        .any(|marker| marker.starts_with(output) || output.starts_with(marker))
+}
+
+/// Parse the tool calls in a model output, calling the functions named `tool_names`
+///
+/// Returns `None` when the output does not contain tool calls or calls another function,
+/// otherwise the tool calls and the text outside of `<tool_call>` blocks, if any.
+pub fn parse_tool_calls(
+    output: &str,
+    tool_names: &[String],
+) -> Option<(Option<String>, Vec<ParsedToolCall>)> {
+    if output.contains(TOOL_CALL_START) {
+        return parse_tool_call_blocks(output, tool_names);
+    }
+
+    let mut output = output.trim();
+    for prefix in TOOL_CALL_PREFIXES {
+        output = output.strip_prefix(prefix).unwrap_or(output).trim_start();
+    }
+
+    let tool_calls = match serde_json::from_str::<Value>(output).ok()? {
+        Value::Array(calls) => calls
+            .iter()
+            .map(|call| parse_tool_call(call, tool_names))
+            .collect::<Option<Vec<ParsedToolCall>>>()?,
+        call => vec![parse_tool_call(&call, tool_names)?],
+    };
+    if tool_calls.is_empty() {
+        return None;
+    }
+
+    Some((None, tool_calls))
+}
+
+fn parse_tool_call_blocks(
+    output: &str,
+    tool_names: &[String],
+) -> Option<(Option<String>, Vec<ParsedToolCall>)> {
+    let mut content = String::new();
+    let mut tool_calls: Vec<ParsedToolCall> = Vec::new();
+    let mut rest = output;
+
+    while let Some(start) = rest.find(TOOL_CALL_START) {
+        content.push_str(&rest[..start]);
+        rest = &rest[start + TOOL_CALL_START.len()..];
+
+        // the end tag is missing when generation stopped at the stop token
+        let (block, after) = rest.split_once(TOOL_CALL_END).unwrap_or((rest, ""));
+        let call: Value = serde_json::from_str(block.trim()).ok()?;
+        tool_calls.push(parse_tool_call(&call, tool_names)?);
+        rest = after;
+    }
+    content.push_str(rest);
+
+    let content = content.trim();
+    let content = (!content.is_empty()).then(|| String::from(content));
+
+    Some((content, tool_calls))
+}
+
+/// Parse a call of one of the functions named `tool_names`, functions without parameters may be
+/// called without `arguments`
+fn parse_tool_call(call: &Value, tool_names: &[String]) -> Option<ParsedToolCall> {
+    let name = call
+        .get("name")?
+        .as_str()
+        .filter(|name| tool_names.iter().any(|tool_name| tool_name == name))?;
+    let arguments = match call.get("arguments").or_else(|| call.get("parameters")) {
+        Some(Value::String(arguments)) => arguments.clone(),
+        Some(arguments @ Value::Object(_)) => arguments.to_string(),
+        None => String::from("{}"),
+        Some(_) => return None,
+    };
+
+    Some(ParsedToolCall {
+        name: String::from(name),
+        arguments,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tool_call(name: &str, arguments: &str) -> ParsedToolCall {
+        ParsedToolCall {
+            name: String::from(name),
+            arguments: String::from(arguments),
+        }
+    }
+
+    fn parse_tool_calls(output: &str) -> Option<(Option<String>, Vec<ParsedToolCall>)> {
+        super::parse_tool_calls(
+            output,
+            &[String::from("get_weather"), String::from("get_time")],
+        )
+    }
+
+    #[test]
+    fn test_parse_tool_calls() {
+        assert_eq!(
+            parse_tool_calls(
+                "Let me check.\n<tool_call>\n{\"name\": \"get_weather\", \"arguments\": {\"city\": \"Paris\"}}\n</tool_call>\n<tool_call>\n{\"name\": \"get_time\", \"arguments\": {}}"
+            ),
+            Some((
+                Some(String::from("Let me check.")),
+                vec![
+                    tool_call("get_weather", r#"{"city":"Paris"}"#),
+                    tool_call("get_time", "{}")
+                ]
+            ))
+        );
+        assert_eq!(
+            parse_tool_calls(r#"{"name": "get_weather", "parameters": {"city": "Paris"}}"#),
+            Some((None, vec![tool_call("get_weather", r#"{"city":"Paris"}"#)]))
+        );
+        assert_eq!(
+            parse_tool_calls(
+                r#"[TOOL_CALLS] [{"name": "get_weather", "arguments": "{\"city\": \"Paris\"}"}]"#
+            ),
+            Some((None, vec![tool_call("get_weather", r#"{"city": "Paris"}"#)]))
+        );
+        assert_eq!(
+            parse_tool_calls(r#"<|python_tag|>{"name": "get_time"}"#),
+            Some((None, vec![tool_call("get_time", "{}")]))
+        );
+
+        assert_eq!(parse_tool_calls("The weather in Paris is sunny."), None);
+        assert_eq!(parse_tool_calls(r#"{"city": "Paris"}"#), None);
+        assert_eq!(parse_tool_calls("[]"), None);
+        assert_eq!(parse_tool_calls("<tool_call>\n{\"name\": "), None);
+    }
+
+    #[test]
+    fn test_parse_tool_calls_unknown_function() {
+        // JSON answers with a name are not tool calls
+        assert_eq!(parse_tool_calls(r#"{"name": "Alice"}"#), None);
+        assert_eq!(
+            parse_tool_calls(r#"[{"name": "get_time"}, {"name": "get_date"}]"#),
+            None
+        );
+        assert_eq!(
+            parse_tool_calls(
+                "<tool_call>\n{\"name\": \"get_date\", \"arguments\": {}}\n</tool_call>"
+            ),
+            None
+        );
+        assert_eq!(
+            super::parse_tool_calls(r#"{"name": "get_time"}"#, &[]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_may_be_tool_call() {
+        assert!(may_be_tool_call(""));
+        assert!(may_be_tool_call("  <tool"));
+        assert!(may_be_tool_call("<tool_call>\n{\"name\""));
+        assert!(may_be_tool_call("{\"name\""));
+        assert!(may_be_tool_call("[TOOL_"));
+        assert!(may_be_tool_call("<|python_tag|>{"));
+        assert!(!may_be_tool_call("The weather"));
+        assert!(!may_be_tool_call("<b>"));
+    }
+}
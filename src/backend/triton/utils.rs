@@ -1,5 +1,322 @@
+use std::pin::Pin;
+
+use async_stream::stream;
+use openai_dive::v1::resources::shared::FinishReason;
+use tonic::codegen::tokio_stream::{self, Stream, StreamExt};
+use tonic::transport::Channel;
+use tonic::Status;
+
+use super::grpc_inference_service_client::GrpcInferenceServiceClient;
+use super::model_infer_request::InferInputTensor;
 use super::model_infer_response::InferOutputTensor;
+use super::request::InferTensorData;
+use super::{ModelInferRequest, ModelInferResponse, ModelStreamInferResponse};
+use crate::config::AiRouterStreamOutput;
+use crate::errors::{transform_triton_status, AiRouterError};
+use crate::request::AiRouterRequestData;
+
+const RANDOM_SEED_INPUT: &str = "random_seed";
+/// Number of tokens generated for each beam
+pub const SEQUENCE_LENGTH_OUTPUT: &str = "sequence_length";
+
+/// Response of one of the requests sent by `stream_infer`, tagged with the index of the request,
+/// `None` after the last response of the request
+pub type TaggedResponse = (usize, Option<Result<ModelStreamInferResponse, Status>>);
 
 pub fn get_output_idx(outputs: &[InferOutputTensor], name: &str) -> Option<usize> {
     outputs.iter().position(|v| v.name == name)
 }
+
+/// Read the number of generated tokens of each beam from the `sequence_length` output of a Triton
+/// response, `None` when the response has no `sequence_length`
+pub fn output_sequence_lengths(infer_response: &ModelInferResponse) -> Option<Vec<u32>> {
+    let idx = get_output_idx(&infer_response.outputs, SEQUENCE_LENGTH_OUTPUT)?;
+    let raw_content = infer_response.raw_output_contents.get(idx)?;
+
+    Some(
+        raw_content
+            .chunks_exact(4)
+            .map(|bytes| {
+                let length = i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                u32::try_from(length).unwrap_or_default()
+            })
+            .collect(),
+    )
+}
+
+/// Number of tokens generated so far, after adding the `sequence_length` of a streamed response
+///
+/// Like the text output, the `sequence_length` of a response counts either all tokens generated
+/// so far or only the new ones. Once a response has no `sequence_length`, the number of tokens is
+/// unknown.
+pub fn add_sequence_length(
+    total: Option<u32>,
+    length: Option<u32>,
+    mode: AiRouterStreamOutput,
+) -> Option<u32> {
+    match mode {
+        AiRouterStreamOutput::Cumulative => length,
+        AiRouterStreamOutput::Delta => Some(total? + length?),
+    }
+}
+
+/// Check the number of choices `n` of a request, returns it as `u32`
+///
+/// Sampled choices with `temperature` 0 would all be the same, beam search returns different
+/// choices with any `temperature`.
+///
+/// # Errors
+/// `AiRouterError::BadRequestError` when `n` is 0 or more than `max_n`, or when `n` is more than 1
+/// without `beam_search` and `temperature` is 0
+pub fn check_n(
+    n: usize,
+    max_n: u32,
+    temperature: Option<f32>,
+    beam_search: bool,
+) -> Result<u32, AiRouterError<String>> {
+    if n == 0 {
+        return Err(AiRouterError::BadRequestError(String::from(
+            "n must be at least 1",
+        )));
+    }
+    let n = u32::try_from(n)
+        .ok()
+        .filter(|n| *n <= max_n)
+        .ok_or_else(|| AiRouterError::BadRequestError(format!("n must be at most {max_n}")))?;
+    if n > 1 && !beam_search && temperature == Some(0.0) {
+        return Err(AiRouterError::BadRequestError(String::from(
+            "n greater than 1 requires a temperature greater than 0, all choices would be the same",
+        )));
+    }
+
+    Ok(n)
+}
+
+/// Copies of `request` generating `n` independent samples, each with its own `random_seed`
+///
+/// The seeds count up from the `random_seed` of the request, so requests with a seed remain
+/// reproducible.
+pub fn sample_requests(request: ModelInferRequest, n: u32) -> Vec<ModelInferRequest> {
+    if n <= 1 {
+        return vec![request];
+    }
+
+    let seed = request
+        .inputs
+        .iter()
+        .find(|input| input.name == RANDOM_SEED_INPUT)
+        .and_then(|input| input.contents.as_ref())
+        .and_then(|contents| contents.uint64_contents.first().copied())
+        .unwrap_or_else(rand::random);
+
+    (0..u64::from(n))
+        .map(|i| {
+            let mut request = request.clone();
+            request
+                .inputs
+                .retain(|input| input.name != RANDOM_SEED_INPUT);
+            request.inputs.push(InferInputTensor {
+                name: String::from(RANDOM_SEED_INPUT),
+                shape: vec![1, 1],
+                datatype: String::from("UINT64"),
+                contents: Some(InferTensorData::UInt64(vec![seed.wrapping_add(i)]).into()),
+                ..Default::default()
+            });
+            request
+        })
+        .collect()
+}
+
+/// Send streaming requests to Triton and merge their responses
+///
+/// The first response of every request is awaited before returning, so requests failing here can
+/// still be retried on a fallback backend.
+pub async fn stream_infer(
+    client: &GrpcInferenceServiceClient<Channel>,
+    requests: Vec<ModelInferRequest>,
+) -> Result<impl Stream<Item = TaggedResponse> + Send + Unpin, AiRouterError<String>> {
+    let streams =
+        futures::future::try_join_all(requests.into_iter().enumerate().map(|(index, request)| {
+            let mut client = client.clone();
+            async move {
+                let request_stream = stream! { yield request };
+                let mut stream = client
+                    .model_stream_infer(tonic::Request::new(request_stream))
+                    .await
+                    .map_err(|e| transform_triton_status(&e))?
+                    .into_inner();
+
+                let first_response = stream
+                    .message()
+                    .await
+                    .map_err(|e| transform_triton_status(&e))?;
+                if let Some(response) = &first_response {
+                    if !response.error_message.is_empty() {
+                        return Err(AiRouterError::BackendError(format!(
+                            "error message received from triton: {}",
+                            response.error_message
+                        )));
+                    }
+                }
+
+                let stream = tokio_stream::iter(first_response.map(Ok))
+                    .chain(stream)
+                    .map(move |response| (index, Some(response)))
+                    .chain(tokio_stream::once((index, None)));
+                Ok::<Pin<Box<dyn Stream<Item = TaggedResponse> + Send>>, AiRouterError<String>>(
+                    Box::pin(stream),
+                )
+            }
+        }))
+        .await?;
+
+    Ok(futures::stream::select_all(streams))
+}
+
+/// Finish reason of a Triton generation
+///
+/// Triton does not report why the generation ended, so the output is considered cut short when it
+/// has `max_tokens` tokens and does not end with one of the `stop` sequences. `completion_tokens`
+/// is the `sequence_length` returned by Triton, or the output counted by the tokenizer of the
+/// model, see `count_completion_tokens`. Outputs of models without either always finish with
+/// `stop`, as their `completion_tokens` are 0.
+pub fn finish_reason(
+    output: &str,
+    stop: &[String],
+    completion_tokens: u32,
+    max_tokens: u32,
+) -> FinishReason {
+    let output = output.trim_end();
+    let stopped = stop
+        .iter()
+        .any(|stop| !stop.is_empty() && output.ends_with(stop.as_str()));
+
+    if completion_tokens < max_tokens || stopped {
+        FinishReason::StopSequenceReached
+    } else {
+        FinishReason::TokenLimitReached
+    }
+}
+
+/// Number of tokens of an output, the `sequence_length` returned by Triton when available
+pub fn count_completion_tokens(
+    sequence_length: Option<u32>,
+    output: &str,
+    request_data: &AiRouterRequestData,
+) -> u32 {
+    sequence_length.unwrap_or_else(|| request_data.count_tokens(output))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::triton::request::Builder;
+
+    fn seeds(requests: &[ModelInferRequest]) -> Vec<u64> {
+        requests
+            .iter()
+            .map(|request| {
+                let inputs: Vec<&InferInputTensor> = request
+                    .inputs
+                    .iter()
+                    .filter(|input| input.name == RANDOM_SEED_INPUT)
+                    .collect();
+                assert_eq!(inputs.len(), 1);
+                inputs[0]
+                    .contents
+                    .as_ref()
+                    .expect("random_seed without contents")
+                    .uint64_contents[0]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sample_requests() {
+        let request = Builder::new()
+            .model_name("test")
+            .input("random_seed", [1, 1], InferTensorData::UInt64(vec![42]))
+            .build()
+            .expect("failed to build request");
+
+        assert_eq!(sample_requests(request.clone(), 1), vec![request.clone()]);
+
+        let requests = sample_requests(request, 3);
+        assert_eq!(seeds(&requests), vec![42, 43, 44]);
+        assert!(requests.iter().all(|request| request.model_name == "test"));
+
+        let request = Builder::new()
+            .model_name("test")
+            .build()
+            .expect("failed to build request");
+        let seeds = seeds(&sample_requests(request, 2));
+        assert_eq!(seeds[1], seeds[0].wrapping_add(1));
+    }
+
+    #[test]
+    fn test_check_n() {
+        assert_eq!(check_n(1, 128, None, false).ok(), Some(1));
+        assert_eq!(check_n(128, 128, Some(0.7), false).ok(), Some(128));
+        assert_eq!(check_n(1, 128, Some(0.0), false).ok(), Some(1));
+        assert_eq!(check_n(4, 128, Some(0.0), true).ok(), Some(4));
+
+        for (n, temperature) in [(0, None), (129, None), (usize::MAX, None), (2, Some(0.0))] {
+            assert!(matches!(
+                check_n(n, 128, temperature, false),
+                Err(AiRouterError::BadRequestError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_finish_reason() {
+        let stop = vec![String::from("</s>"), String::new()];
+
+        assert!(matches!(
+            finish_reason("The capital of France is Paris.", &stop, 7, 16),
+            FinishReason::StopSequenceReached
+        ));
+        assert!(matches!(
+            finish_reason("The capital of France is", &stop, 16, 16),
+            FinishReason::TokenLimitReached
+        ));
+        assert!(matches!(
+            finish_reason("The capital of France is Paris.</s>\n", &stop, 16, 16),
+            FinishReason::StopSequenceReached
+        ));
+        assert!(matches!(
+            finish_reason("The capital of France is", &[], 0, 16),
+            FinishReason::StopSequenceReached
+        ));
+    }
+
+    #[test]
+    fn test_output_sequence_lengths() {
+        let mut infer_response = ModelInferResponse::default();
+        assert_eq!(output_sequence_lengths(&infer_response), None);
+
+        infer_response.outputs.push(InferOutputTensor {
+            name: String::from(SEQUENCE_LENGTH_OUTPUT),
+            datatype: String::from("INT32"),
+            shape: vec![1, 2],
+            ..Default::default()
+        });
+        infer_response
+            .raw_output_contents
+            .push([7i32.to_le_bytes(), 16i32.to_le_bytes()].concat());
+        assert_eq!(output_sequence_lengths(&infer_response), Some(vec![7, 16]));
+    }
+
+    #[test]
+    fn test_add_sequence_length() {
+        let mode = AiRouterStreamOutput::Delta;
+        assert_eq!(add_sequence_length(Some(0), Some(3), mode), Some(3));
+        assert_eq!(add_sequence_length(Some(3), Some(2), mode), Some(5));
+        assert_eq!(add_sequence_length(Some(5), None, mode), None);
+        assert_eq!(add_sequence_length(None, Some(2), mode), None);
+
+        let mode = AiRouterStreamOutput::Cumulative;
+        assert_eq!(add_sequence_length(Some(3), Some(5), mode), Some(5));
+        assert_eq!(add_sequence_length(Some(5), None, mode), None);
+    }
+}
@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::fmt;
 
 use anyhow::{anyhow, Result};
 use clap::Parser;
@@ -7,9 +8,15 @@ use figment::{
     Figment,
 };
 use serde::{Deserialize, Serialize};
-use serde_with::{formats::PreferMany, serde_as, skip_serializing_none, OneOrMany};
+use serde_with::{
+    formats::{PreferMany, PreferOne},
+    serde_as, skip_serializing_none, OneOrMany,
+};
 use uuid::Uuid;
 
+use crate::audio::DEFAULT_CHUNK_OVERLAP;
+use crate::templates;
+
 const DEFAULT_CONFIG_FILE: &str = "/etc/ai-router/config.toml";
 
 pub type AiRouterModels = HashMap<AiRouterModelType, HashMap<String, AiRouterModel>>;
@@ -21,15 +28,76 @@ pub enum AiRouterBackendType {
     Triton,
 }
 
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AiRouterLoadBalancing {
+    LeastOutstanding,
+    Random,
+    #[default]
+    RoundRobin,
+}
+
 #[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum AiRouterModelType {
     AudioSpeech,
     AudioTranscriptions,
+    AudioTranslations,
     ChatCompletions,
+    Completions,
     Embeddings,
 }
 
+/// Model name in responses of a default model used for an unknown model
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AiRouterResponseModelName {
+    /// Name of the default model in the config file
+    Default,
+    /// Name of the model in the client request
+    #[default]
+    Requested,
+}
+
+#[skip_serializing_none]
+#[derive(Clone, Deserialize, Serialize)]
+pub struct AiRouterApiKey {
+    pub key: String,
+    /// Owner of the API key, e.g. the team it was handed out to
+    pub owner: Option<String>,
+    /// Names of the models this key can use, all models if unset
+    pub models: Option<Vec<String>>,
+    /// Types of the models this key can use, all model types if unset
+    pub model_types: Option<Vec<AiRouterModelType>>,
+    /// Unix timestamp in seconds after which the key is rejected
+    pub expires_at: Option<u64>,
+    /// API keys to use for `OpenAI` backends without `api_key`, indexed by backend name
+    pub backend_api_keys: Option<HashMap<String, String>>,
+}
+
+// Don't leak API keys into logs and traces
+impl fmt::Debug for AiRouterApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AiRouterApiKey")
+            .field("owner", &self.owner)
+            .field("models", &self.models)
+            .field("model_types", &self.model_types)
+            .field("expires_at", &self.expires_at)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Text in the `text_output` of streamed responses of Triton models
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AiRouterStreamOutput {
+    /// Every response contains the complete output generated so far
+    #[default]
+    Cumulative,
+    /// Every response contains only the output generated since the previous response
+    Delta,
+}
+
 #[skip_serializing_none]
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct AiRouterBackend {
@@ -37,12 +105,22 @@ pub struct AiRouterBackend {
     #[serde(rename = "type")]
     pub backend_type: AiRouterBackendType,
     pub base_url: String,
+    /// Consecutive failed requests after which the backend is skipped
+    pub circuit_breaker_threshold: Option<u32>,
+    /// Seconds to skip the backend after `circuit_breaker_threshold` consecutive failed requests
+    pub circuit_breaker_timeout: Option<u64>,
     pub default: Option<bool>,
+    /// Seconds between active health checks, disabled when set to 0
+    pub health_check_interval: Option<u64>,
     pub mode: Option<String>,
+    /// Seconds to wait for a response, or for the first chunk of a streaming response
+    pub timeout: Option<u64>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct AiRouterConfigFile {
+    #[serde(default)]
+    pub api_keys: HashMap<String, AiRouterApiKey>,
     pub backends: HashMap<String, AiRouterBackend>,
     pub daemon: AiRouterDaemon,
     pub models: AiRouterModels,
@@ -61,11 +139,27 @@ impl AiRouterConfigFile {
             .merge(Env::prefixed("AI_ROUTER_").split("_"))
             .extract()?;
         if let Err(e) = config.validate() {
-            return Err(anyhow!("config file validation failed: {e}"));
+            return Err(anyhow!("config file validation failed: {e:#}"));
         }
         Ok(config)
     }
 
+    fn check_api_keys(&self) -> Result<()> {
+        let mut keys: Vec<&String> = self.daemon.api_key.iter().collect();
+
+        for (name, api_key) in &self.api_keys {
+            if api_key.key.is_empty() {
+                return Err(anyhow!("API key `{name}` is empty"));
+            }
+            if keys.contains(&&api_key.key) {
+                return Err(anyhow!("API key `{name}` is configured multiple times"));
+            }
+            keys.push(&api_key.key);
+        }
+
+        Ok(())
+    }
+
     fn check_backends(&self) -> Result<()> {
         if self.backends.is_empty() {
             return Err(anyhow!("no backends defined in config file"));
@@ -73,6 +167,87 @@ impl AiRouterConfigFile {
         Ok(())
     }
 
+    fn check_backend_api_keys(&self) -> Result<()> {
+        for (name, api_key) in &self.api_keys {
+            let Some(backend_api_keys) = &api_key.backend_api_keys else {
+                continue;
+            };
+            for backend in backend_api_keys.keys() {
+                if !self.backends.contains_key(backend) {
+                    return Err(anyhow!(
+                        "backend `{backend}` configured for API key `{name}` does not exist"
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_chunking(&self) -> Result<()> {
+        for model_type in self.models.values() {
+            for (model_name, model) in model_type {
+                let Some(chunk_length) = model.chunk_length else {
+                    if model.chunk_overlap.is_some() {
+                        return Err(anyhow!(
+                            "chunk_overlap configured for model `{model_name}` without chunk_length"
+                        ));
+                    }
+                    continue;
+                };
+                let chunk_overlap = model.chunk_overlap.unwrap_or(DEFAULT_CHUNK_OVERLAP);
+                if chunk_overlap >= chunk_length {
+                    return Err(anyhow!(
+                        "chunk_overlap of model `{model_name}` must be smaller than chunk_length"
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_completions_models(&self) -> Result<()> {
+        let Some(models) = self.models.get(&AiRouterModelType::Completions) else {
+            return Ok(());
+        };
+
+        for (model_name, model) in models {
+            if model.prompt_format.is_some() {
+                return Err(anyhow!(
+                    "prompt_format configured for completions model `{model_name}`, it is only supported for chat_completions models"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_prompt_formats(&self) -> Result<()> {
+        for model_type in self.models.values() {
+            for (model_name, model) in model_type {
+                let Some(prompt_format) = &model.prompt_format else {
+                    continue;
+                };
+                if templates::is_builtin(prompt_format) {
+                    continue;
+                }
+
+                let path =
+                    templates::template_path(self.daemon.template_dir.as_deref(), prompt_format);
+                if !path.is_file() {
+                    return Err(anyhow!(
+                        "prompt_format `{prompt_format}` of model `{model_name}` is neither a built-in template nor a template file"
+                    ));
+                }
+            }
+        }
+
+        // compile the templates, so broken ones fail at startup instead of on every request
+        templates::PromptTemplates::new(self)?;
+
+        Ok(())
+    }
+
     fn check_default_backends(&self) -> Result<()> {
         if self.num_default_backends() > 1 {
             return Err(anyhow!("multiple backends set as default"));
@@ -99,18 +274,33 @@ impl AiRouterConfigFile {
     fn check_model_backends(&self) -> Result<()> {
         for model_type in self.models.values() {
             for (model_name, model) in model_type {
-                if let Some(model_backend) = &model.backend {
-                    if !self.backends.contains_key(model_backend) {
+                if let Some(model_backends) = &model.backend {
+                    if model_backends.is_empty() {
                         return Err(anyhow!(
-                            "backend `{}` configured for model `{model_name}` does not exist",
-                            model_backend
+                            "empty list of backends configured for model `{model_name}`"
                         ));
                     }
+                    for model_backend in model_backends {
+                        if !self.backends.contains_key(model_backend) {
+                            return Err(anyhow!(
+                                "backend `{}` configured for model `{model_name}` does not exist",
+                                model_backend
+                            ));
+                        }
+                    }
                 } else if self.num_default_backends() < 1 {
                     return Err(anyhow!(
                         "model `{model_name}` has no backend configured but no default backend exists",
                     ));
                 }
+                for fallback in model.fallback.iter().flatten() {
+                    if !self.backends.contains_key(&fallback.backend) {
+                        return Err(anyhow!(
+                            "fallback backend `{}` configured for model `{model_name}` does not exist",
+                            fallback.backend
+                        ));
+                    }
+                }
             }
         }
         Ok(())
@@ -136,11 +326,16 @@ impl AiRouterConfigFile {
     }
 
     fn validate(&self) -> Result<()> {
+        self.check_api_keys()?;
         self.check_backends()?;
+        self.check_backend_api_keys()?;
         self.check_models()?;
+        self.check_chunking()?;
+        self.check_completions_models()?;
         self.check_default_backends()?;
         self.check_default_models()?;
         self.check_model_backends()?;
+        self.check_prompt_formats()?;
 
         Ok(())
     }
@@ -161,18 +356,61 @@ pub struct AiRouterDaemon {
     #[serde(default = "default_max_body_size")]
     pub max_body_size: usize,
     pub otlp_endpoint: Option<String>,
+    /// Require an API key for the `/health_check` endpoint
+    pub protect_health_check: Option<bool>,
+    /// Require an API key for the `/metrics` endpoint
+    pub protect_metrics: Option<bool>,
+    /// Directory of the template files used as `prompt_format` of models
+    pub template_dir: Option<String>,
+}
+
+#[skip_serializing_none]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AiRouterFallback {
+    pub backend: String,
+    /// Override model in request sent to the fallback backend, `backend_model` of the model if unset
+    pub backend_model: Option<String>,
 }
 
+#[serde_as]
 #[skip_serializing_none]
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct AiRouterModel {
-    pub backend: Option<String>,
+    /// Backend(s) serving the model, requests are balanced across them using `load_balancing`
+    #[serde_as(as = "Option<OneOrMany<_, PreferOne>>")]
+    pub backend: Option<Vec<String>>,
     pub backend_model: Option<String>,
+    /// Split audio of `audio_transcriptions` and `audio_translations` models into chunks of this
+    /// many seconds
+    pub chunk_length: Option<u32>,
+    /// Seconds of overlap between chunks, see `chunk_length`
+    pub chunk_overlap: Option<u32>,
     pub default: Option<bool>,
+    /// Backends to try in order when the selected backend fails
+    pub fallback: Option<Vec<AiRouterFallback>>,
+    /// Pass the `response_format` of requests to Triton `chat_completions` models as guided
+    /// decoding inputs, the JSON schema is added to the prompt otherwise
+    pub guided_decoding: Option<bool>,
     pub hf_model_name: Option<String>,
+    pub load_balancing: Option<AiRouterLoadBalancing>,
     pub max_input: Option<usize>,
+    /// Maximum number of choices `n` of requests to Triton `chat_completions` and `completions`
+    /// models, 128 if unset
+    pub max_n: Option<u32>,
     pub max_tokens: Option<u32>,
+    /// Prompt template of Triton `chat_completions` models, the name of a built-in template or the
+    /// path of a Jinja template or `tokenizer_config.json` file relative to `template_dir`
     pub prompt_format: Option<String>,
+    /// Times to repeat a request to a Triton `chat_completions` model when the output does not
+    /// match the `response_format`, 0 if unset
+    pub response_format_retries: Option<u32>,
+    /// Model name in responses when used as default model for an unknown model
+    pub response_model_name: Option<AiRouterResponseModelName>,
+    /// Sample rate of the audio returned by Triton `audio_speech` models, 24000 if unset
+    pub sample_rate: Option<u32>,
+    /// Text in the streamed responses of Triton `chat_completions` and `completions` models,
+    /// `cumulative` if unset
+    pub stream_output: Option<AiRouterStreamOutput>,
 }
 
 #[derive(Parser, Debug, Serialize, Deserialize)]
@@ -213,6 +451,92 @@ mod tests {
         }
     }
 
+    #[test]
+    #[should_panic(
+        expected = "config file validation failed: chunk_overlap of model `whisper` must be smaller than chunk_length"
+    )]
+    fn test_chunk_overlap_invalid() {
+        let config: Result<AiRouterConfigFile> =
+            AiRouterConfigFile::parse(String::from("tests/ai-router.toml.chunk_overlap_invalid"));
+
+        match config {
+            Ok(o) => println!(
+                "{}",
+                serde_json::to_string_pretty(&o).expect("failed to convert config file to JSON")
+            ),
+            Err(e) => panic!("{e:?}"),
+        }
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "config file validation failed: prompt_format `mistrall` of model `mistral` is neither a built-in template nor a template file"
+    )]
+    fn test_prompt_format_invalid() {
+        let config: Result<AiRouterConfigFile> =
+            AiRouterConfigFile::parse(String::from("tests/ai-router.toml.prompt_format_invalid"));
+
+        match config {
+            Ok(o) => println!(
+                "{}",
+                serde_json::to_string_pretty(&o).expect("failed to convert config file to JSON")
+            ),
+            Err(e) => panic!("{e:?}"),
+        }
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "config file validation failed: failed to load prompt_format `templates.broken_template` of model `broken`: syntax error"
+    )]
+    fn test_prompt_format_broken() {
+        let config: Result<AiRouterConfigFile> =
+            AiRouterConfigFile::parse(String::from("tests/ai-router.toml.prompt_format_broken"));
+
+        match config {
+            Ok(o) => println!(
+                "{}",
+                serde_json::to_string_pretty(&o).expect("failed to convert config file to JSON")
+            ),
+            Err(e) => panic!("{e:?}"),
+        }
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "config file validation failed: prompt_format configured for completions model `base`, it is only supported for chat_completions models"
+    )]
+    fn test_completions_prompt_format() {
+        let config: Result<AiRouterConfigFile> = AiRouterConfigFile::parse(String::from(
+            "tests/ai-router.toml.completions_prompt_format",
+        ));
+
+        match config {
+            Ok(o) => println!(
+                "{}",
+                serde_json::to_string_pretty(&o).expect("failed to convert config file to JSON")
+            ),
+            Err(e) => panic!("{e:?}"),
+        }
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "config file validation failed: API key `team_b` is configured multiple times"
+    )]
+    fn test_duplicate_api_keys() {
+        let config: Result<AiRouterConfigFile> =
+            AiRouterConfigFile::parse(String::from("tests/ai-router.toml.duplicate_api_keys"));
+
+        match config {
+            Ok(o) => println!(
+                "{}",
+                serde_json::to_string_pretty(&o).expect("failed to convert config file to JSON")
+            ),
+            Err(e) => panic!("{e:?}"),
+        }
+    }
+
     #[test]
     #[should_panic(expected = "config file validation failed: multiple backends set as default")]
     fn test_multiple_default_backends() {
@@ -259,6 +583,21 @@ mod tests {
         }
     }
 
+    #[test]
+    #[should_panic(expected = "config file validation failed: fallback backend ")]
+    fn test_model_fallback_invalid() {
+        let config: Result<AiRouterConfigFile> =
+            AiRouterConfigFile::parse(String::from("tests/ai-router.toml.model_fallback_invalid"));
+
+        match config {
+            Ok(o) => println!(
+                "{}",
+                serde_json::to_string_pretty(&o).expect("failed to convert config file to JSON")
+            ),
+            Err(e) => panic!("{e:?}"),
+        }
+    }
+
     #[test]
     #[should_panic(expected = "config file validation failed: no backends defined in config file")]
     fn test_no_backends() {
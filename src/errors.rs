@@ -6,15 +6,25 @@ use axum::{
 use openai_dive::v1::error::APIError;
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DefaultOnError};
+use tonic::{Code, Status};
 
 #[derive(Debug)]
 pub enum AiRouterError<T> {
+    /// Error returned by a backend, e.g. an error message or status of Triton
+    BackendError(String),
     BadRequestError(String),
+    GatewayTimeout(String),
     InputExceededError(String, usize, usize),
     InternalServerError(String),
+    InvalidApiKey(String),
+    /// Output of the model that does not match the constraints of the request, e.g. the schema of
+    /// the `response_format`
+    InvalidOutput(String),
     ModelNotFound(String),
+    ServiceUnavailable(String),
     UnknownUrl(Box<Request<T>>),
-    WrappedOpenAi(OpenAIError),
+    /// Error response of an `OpenAI` backend, with the status code it was returned with
+    WrappedOpenAi(OpenAIError, StatusCode),
 }
 
 impl<E, T> From<E> for AiRouterError<T>
@@ -26,6 +36,20 @@ where
     }
 }
 
+impl<T> AiRouterError<T> {
+    /// Check if the error is a failure of the backend: it cannot be reached, does not respond in
+    /// time or returns a server error
+    ///
+    /// Errors of the request or of the router itself, e.g. a missing tokenizer, are not.
+    pub fn is_backend_failure(&self) -> bool {
+        match self {
+            Self::BackendError(_) | Self::GatewayTimeout(_) | Self::ServiceUnavailable(_) => true,
+            Self::WrappedOpenAi(_, status) => status.is_server_error(),
+            _ => false,
+        }
+    }
+}
+
 impl<T> IntoResponse for AiRouterError<T>
 where
     T: std::fmt::Debug,
@@ -34,6 +58,17 @@ where
         tracing::error!("sending error response to client: {self:?}");
 
         match self {
+            Self::BackendError(msg) => {
+                let error = OpenAIError {
+                    error: OpenAIErrorData {
+                        code: None,
+                        message: msg,
+                        param: None,
+                        r#type: OpenAIErrorType::InternalServerError,
+                    },
+                };
+                (StatusCode::INTERNAL_SERVER_ERROR, Json(error)).into_response()
+            }
             Self::BadRequestError(message) => {
                 let error = OpenAIError {
                     error: OpenAIErrorData {
@@ -45,6 +80,17 @@ where
                 };
                 (StatusCode::BAD_REQUEST, Json(error)).into_response()
             }
+            Self::GatewayTimeout(msg) => {
+                let error = OpenAIError {
+                    error: OpenAIErrorData {
+                        code: None,
+                        message: msg,
+                        param: None,
+                        r#type: OpenAIErrorType::InternalServerError,
+                    },
+                };
+                (StatusCode::GATEWAY_TIMEOUT, Json(error)).into_response()
+            }
             Self::InputExceededError(model, max, input) => {
                 Self::BadRequestError(format!("Maximum input length for model {model} is {max} tokens, however your input is {input} tokens. Please reduce your input.")).into_response()
             }
@@ -59,6 +105,28 @@ where
                 };
                 (StatusCode::INTERNAL_SERVER_ERROR, Json(error)).into_response()
             }
+            Self::InvalidApiKey(message) => {
+                let error = OpenAIError {
+                    error: OpenAIErrorData {
+                        code: Some(OpenAIErrorCode::InvalidApiKey),
+                        message,
+                        param: None,
+                        r#type: OpenAIErrorType::InvalidRequestError,
+                    },
+                };
+                (StatusCode::UNAUTHORIZED, Json(error)).into_response()
+            }
+            Self::InvalidOutput(message) => {
+                let error = OpenAIError {
+                    error: OpenAIErrorData {
+                        code: None,
+                        message,
+                        param: None,
+                        r#type: OpenAIErrorType::InvalidRequestError,
+                    },
+                };
+                (StatusCode::UNPROCESSABLE_ENTITY, Json(error)).into_response()
+            }
             Self::ModelNotFound(model_name) => {
                 let error = OpenAIError {
                     error: OpenAIErrorData {
@@ -70,6 +138,17 @@ where
                 };
                 (StatusCode::NOT_FOUND, Json(error)).into_response()
             }
+            Self::ServiceUnavailable(msg) => {
+                let error = OpenAIError {
+                    error: OpenAIErrorData {
+                        code: None,
+                        message: msg,
+                        param: None,
+                        r#type: OpenAIErrorType::InternalServerError,
+                    },
+                };
+                (StatusCode::SERVICE_UNAVAILABLE, Json(error)).into_response()
+            }
             Self::UnknownUrl(request) => {
                 let error = OpenAIError {
                     error: OpenAIErrorData {
@@ -85,9 +164,7 @@ where
                 };
                 (StatusCode::NOT_FOUND, Json(error)).into_response()
             }
-            Self::WrappedOpenAi(error) => {
-                (StatusCode::INTERNAL_SERVER_ERROR, Json(error)).into_response()
-            }
+            Self::WrappedOpenAi(error, status) => (status, Json(error)).into_response(),
         }
     }
 }
@@ -126,32 +203,69 @@ pub struct OpenAIErrorData {
     pub r#type: OpenAIErrorType,
 }
 
+/// Transform an error received from the `OpenAI` backend client
+///
+/// Errors keep the status code and body returned by the backend. Errors without a status code,
+/// e.g. `APIError::ServerError`, keep their body with `500 Internal Server Error`.
 pub fn transform_openai_dive_apierror(input: &APIError) -> AiRouterError<String> {
-    let default = OpenAIErrorData {
-        code: None,
-        message: String::from("failed to transform error from OpenAI backend client"),
-        param: None,
-        r#type: OpenAIErrorType::InternalServerError,
-    };
-
-    let error: OpenAIErrorData = match input {
-        APIError::AuthenticationError(s)
-        | APIError::BadRequestError(s)
-        | APIError::FileError(s)
-        | APIError::GoneError(s)
+    match input {
+        APIError::AuthenticationError(s) => {
+            transform_openai_error_body(s, StatusCode::UNAUTHORIZED)
+        }
+        APIError::BadRequestError(s) => transform_openai_error_body(s, StatusCode::BAD_REQUEST),
+        APIError::GoneError(s) => transform_openai_error_body(s, StatusCode::GONE),
+        APIError::NotFoundError(s) => transform_openai_error_body(s, StatusCode::NOT_FOUND),
+        APIError::PermissionError(s) => transform_openai_error_body(s, StatusCode::FORBIDDEN),
+        APIError::RateLimitError(s) => {
+            transform_openai_error_body(s, StatusCode::TOO_MANY_REQUESTS)
+        }
+        APIError::UnknownError(status, s) => transform_openai_error_body(
+            s,
+            StatusCode::from_u16(*status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+        ),
+        APIError::FileError(s)
         | APIError::InvalidRequestError(s)
-        | APIError::NotFoundError(s)
         | APIError::ParseError(s)
-        | APIError::PermissionError(s)
-        | APIError::RateLimitError(s)
         | APIError::ServerError(s)
         | APIError::StreamError(s)
-        | APIError::UnknownError(_, s)
-        | APIError::WebSocketError(s) => serde_json::from_str(s).unwrap_or_else(|e| {
-            tracing::error!("failed to deserialize {s}: {e}");
-            default
-        }),
-    };
+        | APIError::WebSocketError(s) => {
+            transform_openai_error_body(s, StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Transform the body of an error response received from an `OpenAI` backend
+///
+/// The body can be the complete error response, or the `error` object in it.
+pub fn transform_openai_error_body(body: &str, status: StatusCode) -> AiRouterError<String> {
+    if let Ok(error) = serde_json::from_str::<OpenAIError>(body) {
+        return AiRouterError::WrappedOpenAi(error, status);
+    }
+
+    let error: OpenAIErrorData = serde_json::from_str(body).unwrap_or_else(|e| {
+        tracing::error!("failed to deserialize {body}: {e}");
+        OpenAIErrorData {
+            code: None,
+            message: String::from("failed to transform error from OpenAI backend client"),
+            param: None,
+            r#type: OpenAIErrorType::InternalServerError,
+        }
+    });
+
+    AiRouterError::WrappedOpenAi(OpenAIError { error }, status)
+}
 
-    AiRouterError::WrappedOpenAi(OpenAIError { error })
+/// Transform an error status received from a Triton backend
+///
+/// Connection errors to the backend are reported as `AiRouterError::ServiceUnavailable`, invalid
+/// requests as `AiRouterError::BadRequestError` and other errors as `AiRouterError::BackendError`.
+pub fn transform_triton_status(status: &Status) -> AiRouterError<String> {
+    match status.code() {
+        Code::Unavailable => AiRouterError::ServiceUnavailable(format!(
+            "Triton backend unavailable: {}",
+            status.message()
+        )),
+        Code::InvalidArgument => AiRouterError::BadRequestError(String::from(status.message())),
+        _ => AiRouterError::BackendError(format!("{status:?}")),
+    }
 }
@@ -1,10 +1,14 @@
+mod audio;
+mod auth;
 pub mod backend;
 pub mod config;
 mod errors;
+mod models;
 mod request;
 pub mod routes;
 pub mod startup;
 mod state;
 pub mod telemetry;
+mod templates;
 mod tokenizers;
 mod utils;
@@ -0,0 +1,312 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use axum::http::HeaderValue;
+use axum::response::Response;
+
+use crate::config::{AiRouterModel, AiRouterResponseModelName};
+
+const WILDCARDS: [char; 2] = ['*', '?'];
+
+/// Response header with the name of the default model used for an unknown model
+pub const DEFAULT_MODEL_HEADER: &str = "x-ai-router-default-model";
+
+/// Model from the config file matching the model name of a client request
+#[derive(Debug)]
+pub struct ModelMatch<'a> {
+    /// Name of the model in the config file, a glob pattern for wildcard matches
+    pub name: &'a str,
+    pub model: Cow<'a, AiRouterModel>,
+    /// Name of the model in the client request
+    pub requested: String,
+    /// The default model was used because the requested model is unknown
+    pub substituted: bool,
+}
+
+impl ModelMatch<'_> {
+    /// Name of the model to check API key access for
+    ///
+    /// Substituted default models are checked by their own name, so API keys restricted to the
+    /// default model can use it for unknown models.
+    pub fn access_name(&self) -> &str {
+        if self.substituted {
+            self.name
+        } else {
+            &self.requested
+        }
+    }
+
+    /// Name of the model to report in responses
+    pub fn response_model_name(&self) -> &str {
+        match self.model.response_model_name {
+            Some(AiRouterResponseModelName::Default) if self.substituted => self.name,
+            _ => &self.requested,
+        }
+    }
+
+    /// Add `DEFAULT_MODEL_HEADER` to the response when the default model was used
+    pub fn set_response_header(&self, response: &mut Response) {
+        if !self.substituted {
+            return;
+        }
+
+        match HeaderValue::from_str(self.name) {
+            Ok(value) => {
+                response.headers_mut().insert(DEFAULT_MODEL_HEADER, value);
+            }
+            Err(e) => tracing::warn!("invalid header value for model {}: {e}", self.name),
+        }
+    }
+}
+
+/// Check if a model name in the config file is a glob pattern
+pub fn is_pattern(name: &str) -> bool {
+    name.contains(WILDCARDS)
+}
+
+/// Find the model for a model name in a client request
+///
+/// Model names in the config file can be glob patterns, where `*` matches any number of
+/// characters and `?` matches a single character. An exact match takes precedence, followed by
+/// the most specific pattern: the one with the most literal characters, then the one with the
+/// fewest wildcards.
+///
+/// The parts of the model name matched by the wildcards of a pattern replace `$1`, `$2`, ... in
+/// `backend_model` of the model and its fallbacks.
+///
+/// When no model matches, the model with `default = true` is used.
+pub fn find_model<'a>(
+    models: &'a HashMap<String, AiRouterModel>,
+    name: &str,
+) -> Option<ModelMatch<'a>> {
+    if let Some((model_name, model)) = models.get_key_value(name) {
+        return Some(ModelMatch {
+            name: model_name,
+            model: Cow::Borrowed(model),
+            requested: String::from(name),
+            substituted: false,
+        });
+    }
+
+    let Some((pattern, model, captures)) = models
+        .iter()
+        .filter(|(pattern, _)| is_pattern(pattern))
+        .filter_map(|(pattern, model)| {
+            glob_match(pattern, name).map(|captures| (pattern, model, captures))
+        })
+        .min_by_key(|(pattern, _, captures)| {
+            let wildcards = captures.len();
+            (
+                std::cmp::Reverse(pattern.chars().count() - wildcards),
+                wildcards,
+                pattern.as_str(),
+            )
+        })
+    else {
+        return find_default_model(models, name);
+    };
+
+    tracing::debug!("model {name} matched pattern {pattern}");
+
+    if captures.is_empty() {
+        return Some(ModelMatch {
+            name: pattern,
+            model: Cow::Borrowed(model),
+            requested: String::from(name),
+            substituted: false,
+        });
+    }
+
+    let mut model = model.clone();
+    model.backend_model = model
+        .backend_model
+        .map(|backend_model| substitute(&backend_model, &captures));
+    for fallback in model.fallback.iter_mut().flatten() {
+        fallback.backend_model = fallback
+            .backend_model
+            .as_ref()
+            .map(|backend_model| substitute(backend_model, &captures));
+    }
+
+    Some(ModelMatch {
+        name: pattern,
+        model: Cow::Owned(model),
+        requested: String::from(name),
+        substituted: false,
+    })
+}
+
+fn find_default_model<'a>(
+    models: &'a HashMap<String, AiRouterModel>,
+    name: &str,
+) -> Option<ModelMatch<'a>> {
+    let (default_name, model) = models
+        .iter()
+        .find(|(_, model)| model.default.unwrap_or(false))?;
+
+    tracing::debug!("model {name} not found, using default model {default_name}");
+
+    // send the name of the default model to the backend instead of the unknown model
+    let model = if model.backend_model.is_some() {
+        Cow::Borrowed(model)
+    } else {
+        let mut model = model.clone();
+        model.backend_model = Some(default_name.clone());
+        Cow::Owned(model)
+    };
+
+    Some(ModelMatch {
+        name: default_name,
+        model,
+        requested: String::from(name),
+        substituted: true,
+    })
+}
+
+/// Match a name against a glob pattern, returning the parts matched by each wildcard
+///
+/// Wildcards match as few characters as possible. Only the last `*` before a mismatch is extended,
+/// so matching never backtracks exponentially, but takes O(pattern × name) time in the worst case.
+pub fn glob_match(pattern: &str, name: &str) -> Option<Vec<String>> {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // position in the name where each character of the pattern matched
+    let mut starts: Vec<usize> = vec![0; pattern.len()];
+    // last `*` and the end of the characters it matches
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut n) = (0, 0);
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                starts[p] = n;
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                starts[p] = n;
+                p += 1;
+                n += 1;
+            }
+            _ => {
+                let (star_p, star_end) = star?;
+                star = Some((star_p, star_end + 1));
+                p = star_p + 1;
+                n = star_end + 1;
+            }
+        }
+    }
+    while pattern.get(p) == Some(&'*') {
+        starts[p] = n;
+        p += 1;
+    }
+    if p < pattern.len() {
+        return None;
+    }
+
+    let captures = pattern
+        .iter()
+        .enumerate()
+        .filter_map(|(i, c)| match c {
+            '*' => {
+                let end = starts.get(i + 1).copied().unwrap_or(name.len());
+                Some(name[starts[i]..end].iter().collect())
+            }
+            '?' => Some(name[starts[i]].to_string()),
+            _ => None,
+        })
+        .collect();
+
+    Some(captures)
+}
+
+fn substitute(backend_model: &str, captures: &[String]) -> String {
+    let mut backend_model = String::from(backend_model);
+
+    // replace $10 before $1
+    for (i, capture) in captures.iter().enumerate().rev() {
+        backend_model = backend_model.replace(&format!("${}", i + 1), capture);
+    }
+
+    backend_model
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{AiRouterConfigFile, AiRouterModelType};
+
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert_eq!(glob_match("gpt-4*", "gpt-4"), Some(vec![String::new()]));
+        assert_eq!(
+            glob_match("gpt-4*", "gpt-4o-mini"),
+            Some(vec![String::from("o-mini")])
+        );
+        assert_eq!(
+            glob_match("*-embedding-?", "text-embedding-3"),
+            Some(vec![String::from("text"), String::from("3")])
+        );
+        assert_eq!(glob_match("gpt-4*", "gpt-3.5-turbo"), None);
+        assert_eq!(glob_match("gpt-?", "gpt-4o"), None);
+        assert_eq!(
+            glob_match("a*b*c", "abxbc"),
+            Some(vec![String::new(), String::from("xb")])
+        );
+        assert_eq!(
+            glob_match("**?", "ab"),
+            Some(vec![String::new(), String::from("a"), String::from("b")])
+        );
+
+        // backtracking into every `*` would take exponential time
+        let name = "a".repeat(100);
+        assert_eq!(
+            glob_match(&"*a".repeat(20), &name).map(|c| c.len()),
+            Some(20)
+        );
+        assert_eq!(glob_match(&format!("{}b", "*a".repeat(20)), &name), None);
+    }
+
+    #[test]
+    fn test_find_model() {
+        let config = AiRouterConfigFile::parse(String::from("tests/ai-router.toml.model_patterns"))
+            .expect("failed to load test config file");
+        let models = &config.models[&AiRouterModelType::ChatCompletions];
+
+        let find_name = |name: &str| find_model(models, name).map(|m| String::from(m.name));
+
+        assert_eq!(find_name("gpt-4o-mini"), Some(String::from("gpt-4o-mini")));
+        assert_eq!(find_name("gpt-4o"), Some(String::from("gpt-4o*")));
+        assert_eq!(find_name("gpt-4-turbo"), Some(String::from("gpt-4*")));
+        assert_eq!(find_name("llama"), None);
+
+        let matched = find_model(models, "gpt-4o-2024-08-06").expect("model not found");
+        assert_eq!(
+            matched.model.backend_model.as_deref(),
+            Some("openai/gpt-4o-2024-08-06")
+        );
+    }
+
+    #[test]
+    fn test_find_default_model() {
+        let config = AiRouterConfigFile::parse(String::from("tests/ai-router.toml.default_model"))
+            .expect("failed to load test config file");
+
+        let models = &config.models[&AiRouterModelType::ChatCompletions];
+        let matched = find_model(models, "gpt-4o").expect("default model not used");
+        assert_eq!(matched.name, "mistral");
+        assert!(matched.substituted);
+        assert_eq!(matched.access_name(), "mistral");
+        assert_eq!(matched.response_model_name(), "gpt-4o");
+        assert_eq!(matched.model.backend_model.as_deref(), Some("mistral"));
+
+        let matched = find_model(models, "mistral").expect("model not found");
+        assert!(!matched.substituted);
+
+        let models = &config.models[&AiRouterModelType::Embeddings];
+        let matched = find_model(models, "text-embedding-3-small").expect("default model not used");
+        assert_eq!(matched.name, "bge");
+        assert_eq!(matched.response_model_name(), "bge");
+    }
+}
@@ -1,32 +1,53 @@
 use std::sync::Arc;
 
+use openai_dive::v1::resources::shared::Usage;
 use tokenizers::Tokenizer;
 use tracing::instrument;
 
-use crate::{config::AiRouterModel, errors::AiRouterError, state::State, tokenizers::Tokenizers};
+use crate::{
+    config::{AiRouterModel, AiRouterStreamOutput},
+    errors::AiRouterError,
+    state::State,
+    templates::PromptTemplate,
+    tokenizers::Tokenizers,
+};
 
-#[derive(Debug)]
+/// Maximum number of choices `n` of a request, unless `max_n` is set for the model
+const DEFAULT_MAX_N: u32 = 128;
+
+#[derive(Clone, Debug)]
 pub struct AiRouterRequestData {
+    pub guided_decoding: bool,
     pub max_input: Option<usize>,
+    pub max_n: u32,
     pub max_tokens: Option<u32>,
     pub original_model: Option<String>,
+    pub prompt_template: Option<PromptTemplate>,
     pub prompt_tokens: usize,
-    pub tokenizer: Option<Tokenizer>,
+    pub response_format_retries: u32,
+    pub stream_output: AiRouterStreamOutput,
+    pub tokenizer: Option<Arc<Tokenizer>>,
 }
 
 impl AiRouterRequestData {
     pub const fn new() -> Self {
         Self {
+            guided_decoding: false,
             max_input: None,
+            max_n: DEFAULT_MAX_N,
             max_tokens: None,
             original_model: None,
+            prompt_template: None,
             prompt_tokens: 0,
+            response_format_retries: 0,
+            stream_output: AiRouterStreamOutput::Cumulative,
             tokenizer: None,
         }
     }
 
     /// # Errors
     /// `AiRouterError::InternalServerError` when `max_input` is set for the model but `hf_model_name is not`
+    /// `AiRouterError::InternalServerError` when the prompt template of the model failed to load
     #[instrument(level = "debug", skip(model, model_name, state))]
     pub fn build(
         model: &AiRouterModel,
@@ -37,25 +58,72 @@ impl AiRouterRequestData {
 
         request_data.original_model = Some(String::from(model_name));
 
+        request_data.guided_decoding = model.guided_decoding.unwrap_or(false);
+        request_data.max_n = model.max_n.unwrap_or(DEFAULT_MAX_N);
+        request_data.response_format_retries = model.response_format_retries.unwrap_or(0);
+        request_data.stream_output = model.stream_output.unwrap_or_default();
+
+        if let Some(hf_model_name) = &model.hf_model_name {
+            request_data.tokenizer = Tokenizers::get(&state.tokenizers, hf_model_name);
+        }
+
         if let Some(max_input) = model.max_input {
-            if let Some(hf_model_name) = model.hf_model_name.clone() {
-                request_data.max_input = Some(max_input);
-                request_data.tokenizer = Tokenizers::get(&state.tokenizers, &hf_model_name);
-            } else {
+            if model.hf_model_name.is_none() {
                 return Err(AiRouterError::InternalServerError::<String>(String::from(
                     "model parameter max_input requires hf_model_name",
                 )));
             }
+            request_data.max_input = Some(max_input);
         }
 
         if let Some(max_tokens) = model.max_tokens {
             request_data.max_tokens = Some(max_tokens);
         }
 
+        if let Some(prompt_format) = &model.prompt_format {
+            let Some(template) = state.templates.get(prompt_format) else {
+                return Err(AiRouterError::InternalServerError::<String>(format!(
+                    "prompt template {prompt_format} is not available",
+                )));
+            };
+            request_data.prompt_template = Some(template);
+        }
+
         Ok(request_data)
     }
+
+    /// Number of tokens in `text` according to the tokenizer of the model, 0 for models without
+    /// `hf_model_name`
+    pub fn count_tokens(&self, text: &str) -> u32 {
+        let Some(tokenizer) = &self.tokenizer else {
+            return 0;
+        };
+
+        match tokenizer.encode(text, false) {
+            Ok(encoded) => u32::try_from(encoded.len()).unwrap_or(u32::MAX),
+            Err(e) => {
+                tracing::warn!("failed to count tokens: {e}");
+                0
+            }
+        }
+    }
+
+    /// Usage of a request with `completion_tokens` generated tokens
+    pub fn usage(&self, completion_tokens: u32) -> Usage {
+        let prompt_tokens = u32::try_from(self.prompt_tokens).unwrap_or(u32::MAX);
+
+        Usage {
+            prompt_tokens: Some(prompt_tokens),
+            completion_tokens: Some(completion_tokens),
+            total_tokens: prompt_tokens.saturating_add(completion_tokens),
+            prompt_tokens_details: None,
+            completion_tokens_details: None,
+        }
+    }
 }
 
+/// Count the tokens of the request input in `prompt_tokens` and check them against `max_input`
+///
 /// # Errors
 /// `AiRouterError::InputExceededError` when number of tokens in request exceeds `max_input` for the model
 /// `AiRouterError::InternalServerError` when `max_input` is set for the model but the tokenizer is not available or failed to encode the request input
@@ -68,26 +136,39 @@ pub fn check_input_cc(
         .original_model
         .clone()
         .unwrap_or_else(|| String::from(model));
+
+    let encoded = request_data
+        .tokenizer
+        .as_ref()
+        .map(|tokenizer| tokenizer.encode(input, false));
+    if let Some(Ok(encoded)) = &encoded {
+        request_data.prompt_tokens = encoded.get_tokens().len();
+    }
+
     if let Some(max_input) = request_data.max_input {
-        if let Some(tokenizer) = &request_data.tokenizer {
-            if let Ok(encoded) = tokenizer.encode(input, false) {
-                request_data.prompt_tokens = encoded.get_tokens().len();
-                if request_data.prompt_tokens > max_input {
-                    return Err(AiRouterError::InputExceededError::<String>(
-                        model,
-                        max_input,
-                        request_data.prompt_tokens,
-                    ));
-                }
-                return Ok(());
+        match encoded {
+            Some(Ok(_)) => {}
+            Some(Err(_)) => {
+                return Err(AiRouterError::InternalServerError::<String>(format!(
+                    "max_input set for model {model} but tokenizer failed to encode the request input"
+                )));
+            }
+            None => {
+                return Err(AiRouterError::InternalServerError::<String>(format!(
+                    "max_input set for model {model} but tokenizer is not available",
+                )));
             }
-            return Err(AiRouterError::InternalServerError::<String>(format!(
-                "max_input set for model {model} but tokenizer failed to encode the request input"
-            )));
         }
-        return Err(AiRouterError::InternalServerError::<String>(format!(
-            "max_input set for model {model} but tokenizer is not available",
-        )));
+        if request_data.prompt_tokens > max_input {
+            return Err(AiRouterError::InputExceededError::<String>(
+                model,
+                max_input,
+                request_data.prompt_tokens,
+            ));
+        }
+    } else if let Some(Err(e)) = encoded {
+        tracing::warn!("failed to count tokens of the request input: {e}");
     }
+
     Ok(())
 }
@@ -1,10 +1,11 @@
+use std::future::Future;
 use std::io::Read;
 use std::sync::Arc;
 
-use anyhow::Context;
+use axum::extract::multipart::Field;
 use axum::extract::{Multipart, State as AxumState};
 use axum::response::Response;
-use axum::Json;
+use axum::{Extension, Json};
 use bytes::Bytes;
 use openai_dive::v1::resources::audio::{
     AudioOutputFormat, AudioSpeechParameters, AudioTranscriptionParameters, TimestampGranularity,
@@ -12,40 +13,66 @@ use openai_dive::v1::resources::audio::{
 use openai_dive::v1::resources::shared::{FileUpload, FileUploadBytes};
 use tracing::instrument;
 
+use crate::audio::transcription::{
+    stream_response, transcribe_chunked, transcribe_chunked_stream, Transcription,
+    TranscriptionTask,
+};
+use crate::audio::{
+    decode_upload, prepare_file_upload, DecodedAudio, DEFAULT_CHUNK_OVERLAP, WHISPER_SAMPLE_RATE,
+};
+use crate::auth::ApiKeyAccess;
 use crate::backend::openai::routes as openai_routes;
+use crate::backend::selector::send_with_fallback;
+use crate::backend::triton::routes as triton_routes;
 use crate::config::AiRouterModelType;
 use crate::errors::AiRouterError;
+use crate::models::find_model;
 use crate::state::{BackendTypes, State};
-use crate::utils::get_file_extension;
 
 pub async fn speech(
     AxumState(state): AxumState<Arc<State>>,
-    Json(mut parameters): Json<AudioSpeechParameters>,
+    Extension(api_key): Extension<ApiKeyAccess>,
+    Json(parameters): Json<AudioSpeechParameters>,
 ) -> Result<Response, AiRouterError<String>> {
     if let Some(models) = state.config.models.get(&AiRouterModelType::AudioSpeech) {
-        if let Some(model) = models.get(&parameters.model) {
-            if let Some(backend_model) = model.backend_model.clone() {
-                parameters.model = backend_model;
-            }
-
-            let model_backend = model.backend.as_ref().map_or("default", |m| m);
-
-            let Some(backend) = state.backends.get(model_backend) else {
-                return Err(AiRouterError::InternalServerError::<String>(format!(
-                    "backend {model_backend} not found"
-                )));
-            };
-
-            match &backend.client {
-                BackendTypes::OpenAI(c) => {
-                    return openai_routes::audio::speech(c, parameters).await;
-                }
-                BackendTypes::Triton(_c) => {
-                    return Err(AiRouterError::BadRequestError::<String>(String::from(
-                        "create speech to Triton backend not implemented yet",
-                    )));
+        if let Some(matched) = find_model(models, &parameters.model)
+            .filter(|m| api_key.can_use(&AiRouterModelType::AudioSpeech, m.access_name()))
+        {
+            let model = matched.model.as_ref();
+
+            let selected = state.selector.select_with_fallback(
+                &state.backends,
+                &AiRouterModelType::AudioSpeech,
+                matched.name,
+                model,
+            )?;
+
+            let sample_rate = model.sample_rate;
+
+            let mut response = send_with_fallback(selected, |selected| {
+                let mut parameters = parameters.clone();
+                let api_key = api_key.clone();
+
+                async move {
+                    if let Some(backend_model) = selected.backend_model {
+                        parameters.model = backend_model;
+                    }
+
+                    match &selected.backend.client {
+                        BackendTypes::OpenAI(c) => {
+                            let c = selected.backend.openai_client(c, &api_key)?;
+                            openai_routes::audio::speech(&c, parameters).await
+                        }
+                        BackendTypes::Triton(c) => {
+                            triton_routes::audio::speech(c.clone(), parameters, sample_rate).await
+                        }
+                    }
                 }
-            }
+            })
+            .await;
+            matched.set_response_header(&mut response);
+
+            return Ok(response);
         }
     }
 
@@ -57,49 +84,184 @@ pub async fn speech(
 #[instrument(level = "debug", skip(state, multipart))]
 pub async fn transcriptions(
     AxumState(state): AxumState<Arc<State>>,
+    Extension(api_key): Extension<ApiKeyAccess>,
     // Multipart must be the last argument
     // <https://github.com/tokio-rs/axum/discussions/1600>
     multipart: Multipart,
 ) -> Result<Response, AiRouterError<String>> {
-    let mut parameters = match build_transcription_parameters(multipart).await {
-        Ok(o) => o,
-        Err(e) => {
-            return Err(e);
-        }
-    };
+    let parameters = build_transcription_parameters(multipart).await?;
 
-    if let Some(models) = state
-        .config
-        .models
-        .get(&AiRouterModelType::AudioTranscriptions)
-    {
-        if let Some(model) = models.get(&parameters.model) {
-            if let Some(backend_model) = model.backend_model.clone() {
-                parameters.model = backend_model;
-            }
+    transcribe(&state, &api_key, parameters, TranscriptionTask::Transcribe).await
+}
 
-            let model_backend = model.backend.as_ref().map_or("default", |m| m);
+#[instrument(level = "debug", skip(state, multipart))]
+pub async fn translations(
+    AxumState(state): AxumState<Arc<State>>,
+    Extension(api_key): Extension<ApiKeyAccess>,
+    // Multipart must be the last argument
+    // <https://github.com/tokio-rs/axum/discussions/1600>
+    multipart: Multipart,
+) -> Result<Response, AiRouterError<String>> {
+    let parameters = build_transcription_parameters(multipart).await?;
 
-            let Some(backend) = state.backends.get(model_backend) else {
-                return Err(AiRouterError::InternalServerError::<String>(format!(
-                    "backend {model_backend} not found"
-                )));
+    transcribe(&state, &api_key, parameters, TranscriptionTask::Translate).await
+}
+
+/// Transcriptions and translations share the request format and only differ in the task
+async fn transcribe(
+    state: &State,
+    api_key: &ApiKeyAccess,
+    mut parameters: AudioTranscriptionParameters,
+    task: TranscriptionTask,
+) -> Result<Response, AiRouterError<String>> {
+    let model_type = match task {
+        TranscriptionTask::Transcribe => AiRouterModelType::AudioTranscriptions,
+        TranscriptionTask::Translate => AiRouterModelType::AudioTranslations,
+    };
+
+    if let Some(models) = state.config.models.get(&model_type) {
+        if let Some(matched) = find_model(models, &parameters.model)
+            .filter(|m| api_key.can_use(&model_type, m.access_name()))
+        {
+            let model = matched.model.as_ref();
+
+            let selected = state.selector.select_with_fallback(
+                &state.backends,
+                &model_type,
+                matched.name,
+                model,
+            )?;
+
+            // decode once, the chunks are sent to whichever backend is selected
+            let chunking = model.chunk_length.map(|length| {
+                let overlap = model.chunk_overlap.unwrap_or(DEFAULT_CHUNK_OVERLAP);
+                (length, overlap)
+            });
+            let audio = match chunking {
+                Some(_) => Some(Arc::new(
+                    decode_upload(&parameters.file, WHISPER_SAMPLE_RATE).await?,
+                )),
+                None => None,
             };
+            // only OpenAI backends need the file in a format they accept, Triton decodes it
+            if audio.is_none()
+                && selected
+                    .iter()
+                    .any(|s| matches!(s.backend.client, BackendTypes::OpenAI(_)))
+            {
+                parameters.file = prepare_file_upload(parameters.file).await?;
+            }
 
-            match &backend.client {
-                BackendTypes::OpenAI(c) => {
-                    return openai_routes::audio::transcriptions(c, parameters).await
-                }
-                BackendTypes::Triton(_c) => {
-                    return Err(AiRouterError::InternalServerError::<String>(String::from(
-                        "audio transcriptions to Triton backend not implemented yet",
-                    )));
+            let mut response = send_with_fallback(selected, |selected| {
+                let mut parameters = parameters.clone();
+                let api_key = api_key.clone();
+                let audio = audio.clone();
+
+                async move {
+                    if let Some(backend_model) = selected.backend_model {
+                        parameters.model = backend_model;
+                    }
+
+                    if let (Some((length, overlap)), Some(audio)) = (chunking, audio) {
+                        return match &selected.backend.client {
+                            BackendTypes::OpenAI(c) => {
+                                let c = selected.backend.openai_client(c, &api_key)?;
+                                let chunk_parameters = parameters.clone();
+                                respond_chunked(
+                                    &audio,
+                                    length,
+                                    overlap,
+                                    &parameters,
+                                    move |chunk| {
+                                        openai_routes::audio::transcribe(
+                                            c.clone(),
+                                            chunk_parameters.clone(),
+                                            chunk,
+                                            task,
+                                        )
+                                    },
+                                )
+                                .await
+                            }
+                            BackendTypes::Triton(c) => {
+                                let c = c.clone();
+                                let chunk_parameters = parameters.clone();
+                                respond_chunked(
+                                    &audio,
+                                    length,
+                                    overlap,
+                                    &parameters,
+                                    move |chunk| {
+                                        let c = c.clone();
+                                        let parameters = chunk_parameters.clone();
+                                        async move {
+                                            triton_routes::audio::transcribe(
+                                                c,
+                                                &parameters.model,
+                                                &chunk,
+                                                task,
+                                                parameters.language.as_deref(),
+                                                parameters.prompt.as_deref(),
+                                            )
+                                            .await
+                                        }
+                                    },
+                                )
+                                .await
+                            }
+                        };
+                    }
+
+                    match &selected.backend.client {
+                        BackendTypes::OpenAI(c) => {
+                            let c = selected.backend.openai_client(c, &api_key)?;
+                            match task {
+                                TranscriptionTask::Transcribe => {
+                                    openai_routes::audio::transcriptions(&c, parameters).await
+                                }
+                                TranscriptionTask::Translate => {
+                                    openai_routes::audio::translations(&c, parameters).await
+                                }
+                            }
+                        }
+                        BackendTypes::Triton(c) => {
+                            triton_routes::audio::transcriptions(c.clone(), parameters, task).await
+                        }
+                    }
                 }
-            }
+            })
+            .await;
+            matched.set_response_header(&mut response);
+
+            return Ok(response);
         }
     }
 
-    return Err(AiRouterError::ModelNotFound::<String>(parameters.model));
+    Err(AiRouterError::ModelNotFound::<String>(parameters.model))
+}
+
+/// Transcribe audio in chunks, streaming the text of each chunk if requested
+async fn respond_chunked<F, Fut>(
+    audio: &DecodedAudio,
+    length: u32,
+    overlap: u32,
+    parameters: &AudioTranscriptionParameters,
+    transcribe: F,
+) -> Result<Response, AiRouterError<String>>
+where
+    F: FnMut(DecodedAudio) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<Transcription, AiRouterError<String>>> + Send + 'static,
+{
+    if parameters.stream == Some(true) {
+        return stream_response(transcribe_chunked_stream(
+            audio, length, overlap, transcribe,
+        ))
+        .await;
+    }
+
+    let transcription = transcribe_chunked(audio, length, overlap, transcribe).await?;
+
+    Ok(transcription.into_response(parameters.response_format.as_ref()))
 }
 
 #[instrument(level = "debug", skip(multipart))]
@@ -125,50 +287,53 @@ pub async fn build_transcription_parameters(
         tracing::trace!("{field:#?}");
         let field_name = field
             .name()
-            .ok_or(AiRouterError::InternalServerError::<String>(String::from(
+            .ok_or(AiRouterError::BadRequestError::<String>(String::from(
                 "failed to read field name",
             )))?
             .to_string();
 
         if field_name == "file" {
-            let filename: String =
-                String::from(field.file_name().context("failed to read field filename")?);
+            let filename: String = String::from(field.file_name().ok_or_else(|| {
+                AiRouterError::BadRequestError::<String>(String::from(
+                    "failed to read field filename",
+                ))
+            })?);
 
-            is_audio_format_supported(&filename)?;
+            let field_data_vec =
+                get_field_data_vec(&read_field(field, &field_name).await?, &field_name)?;
 
-            let field_data_vec = get_field_data_vec(&field.bytes().await?, &field_name)?;
-            let bytes = FileUploadBytes {
+            parameters.file = FileUpload::Bytes(FileUploadBytes {
                 bytes: Bytes::copy_from_slice(&field_data_vec),
                 filename,
-            };
-
-            parameters.file = FileUpload::Bytes(bytes);
+            });
         } else if field_name == "language" {
-            let field_data_vec = get_field_data_vec(&field.bytes().await?, &field_name)?;
-
-            parameters.language = Some(String::from_utf8(field_data_vec)?);
+            parameters.language = Some(read_text_field(field, &field_name).await?);
         } else if field_name == "model" {
-            let field_data_vec = get_field_data_vec(&field.bytes().await?, &field_name)?;
-
-            parameters.model = String::from_utf8(field_data_vec)?;
+            parameters.model = read_text_field(field, &field_name).await?;
         } else if field_name == "prompt" {
-            let field_data_vec = get_field_data_vec(&field.bytes().await?, &field_name)?;
-
-            parameters.prompt = Some(String::from_utf8(field_data_vec)?);
+            parameters.prompt = Some(read_text_field(field, &field_name).await?);
         } else if field_name == "response_format" {
-            let field_data_vec = get_field_data_vec(&field.bytes().await?, &field_name)?;
-            let response_format = String::from_utf8(field_data_vec)?;
-            let response_format = serde_plain::from_str(&response_format)?;
+            let response_format = read_text_field(field, &field_name).await?;
+            let response_format = serde_plain::from_str(&response_format)
+                .map_err(|e| invalid_field(&field_name, e))?;
 
             parameters.response_format = Some(response_format);
+        } else if field_name == "stream" {
+            let stream = read_text_field(field, &field_name).await?;
+
+            parameters.stream = Some(stream.parse().map_err(|e| invalid_field(&field_name, e))?);
         } else if field_name == "temperature" {
-            let field_data_vec = get_field_data_vec(&field.bytes().await?, &field_name)?;
+            let temperature = read_text_field(field, &field_name).await?;
 
-            parameters.temperature = Some(String::from_utf8(field_data_vec)?.parse()?);
+            parameters.temperature = Some(
+                temperature
+                    .parse()
+                    .map_err(|e| invalid_field(&field_name, e))?,
+            );
         } else if field_name == "timestamp_granularities[]" {
-            let field_data_vec = get_field_data_vec(&field.bytes().await?, &field_name)?;
-            let granularity = String::from_utf8(field_data_vec)?;
-            let granularity = serde_plain::from_str(&granularity)?;
+            let granularity = read_text_field(field, &field_name).await?;
+            let granularity =
+                serde_plain::from_str(&granularity).map_err(|e| invalid_field(&field_name, e))?;
 
             timestamp_granularities.push(granularity);
         }
@@ -183,22 +348,20 @@ pub async fn build_transcription_parameters(
     Ok(parameters)
 }
 
-fn is_audio_format_supported(filename: &str) -> Result<(), AiRouterError<String>> {
-    const SUPPORTED_EXTENSIONS: [&str; 10] = [
-        "flac", "m4a", "mp3", "mp4", "mpeg", "mpga", "ogg", "ogm", "wav", "webm",
-    ];
-
-    let extension = get_file_extension(filename)?;
+/// Read the data of a form field, the client sent an invalid request if that fails
+async fn read_field(field: Field<'_>, name: &str) -> Result<Bytes, AiRouterError<String>> {
+    field.bytes().await.map_err(|e| invalid_field(name, e))
+}
 
-    tracing::debug!("filename: {filename} - extension: {extension}");
+/// Read a form field that must be UTF-8 text
+async fn read_text_field(field: Field<'_>, name: &str) -> Result<String, AiRouterError<String>> {
+    let field_data_vec = get_field_data_vec(&read_field(field, name).await?, name)?;
 
-    if !SUPPORTED_EXTENSIONS.contains(&extension) {
-        return Err(AiRouterError::InternalServerError::<String>(format!(
-            "extension {extension:?} not supported",
-        )));
-    }
+    String::from_utf8(field_data_vec).map_err(|e| invalid_field(name, e))
+}
 
-    Ok(())
+fn invalid_field(name: &str, e: impl std::fmt::Display) -> AiRouterError<String> {
+    AiRouterError::BadRequestError(format!("invalid {name} field: {e}"))
 }
 
 fn get_field_data_vec(data: &Bytes, name: &str) -> Result<Vec<u8>, AiRouterError<String>> {
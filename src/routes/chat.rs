@@ -2,56 +2,79 @@ use std::sync::Arc;
 
 use axum::extract::State as AxumState;
 use axum::response::Response;
+use axum::Extension;
 use axum::Json;
 use openai_dive::v1::resources::chat::ChatCompletionParameters;
 use tracing::instrument;
 
+use crate::auth::ApiKeyAccess;
 use crate::backend::openai::routes as openai_routes;
+use crate::backend::selector::send_with_fallback;
 use crate::backend::triton::routes as triton_routes;
 use crate::config::AiRouterModelType;
 use crate::errors::AiRouterError;
+use crate::models::find_model;
 use crate::request::AiRouterRequestData;
 use crate::state::{BackendTypes, State};
 
 #[instrument(skip(state, request))]
 pub async fn completion(
     AxumState(state): AxumState<Arc<State>>,
-    mut request: Json<ChatCompletionParameters>,
+    Extension(api_key): Extension<ApiKeyAccess>,
+    request: Json<ChatCompletionParameters>,
 ) -> Result<Response, AiRouterError<String>> {
     if let Some(models) = state.config.models.get(&AiRouterModelType::ChatCompletions) {
-        if let Some(model) = models.get(&request.model) {
-            let mut request_data = AiRouterRequestData::build(model, &request.model, &state)?;
-
-            if let Some(backend_model) = model.backend_model.clone() {
-                request.model = backend_model;
-            }
-
-            let model_backend = model.backend.as_ref().map_or("default", |m| m);
-
-            let Some(backend) = state.backends.get(model_backend) else {
-                return Err(AiRouterError::InternalServerError::<String>(format!(
-                    "backend {model_backend} not found"
-                )));
-            };
-
-            match &backend.client {
-                BackendTypes::OpenAI(c) => {
-                    return Ok(openai_routes::chat::wrap_chat_completion(
-                        c.clone(),
-                        request,
-                        &request_data,
-                    )
-                    .await);
-                }
-                BackendTypes::Triton(c) => {
-                    return Ok(triton_routes::chat::compat_chat_completions(
-                        c.clone(),
-                        request,
-                        &mut request_data,
-                    )
-                    .await);
+        if let Some(matched) = find_model(models, &request.model)
+            .filter(|m| api_key.can_use(&AiRouterModelType::ChatCompletions, m.access_name()))
+        {
+            let model = matched.model.as_ref();
+
+            let request_data =
+                AiRouterRequestData::build(model, matched.response_model_name(), &state)?;
+
+            let selected = state.selector.select_with_fallback(
+                &state.backends,
+                &AiRouterModelType::ChatCompletions,
+                matched.name,
+                model,
+            )?;
+
+            let mut response = send_with_fallback(selected, |selected| {
+                let mut request = request.clone();
+                let mut request_data = request_data.clone();
+                let api_key = api_key.clone();
+
+                async move {
+                    if let Some(backend_model) = selected.backend_model {
+                        request.model = backend_model;
+                    }
+
+                    let response = match &selected.backend.client {
+                        BackendTypes::OpenAI(c) => {
+                            openai_routes::chat::wrap_chat_completion(
+                                selected.backend.openai_client(c, &api_key)?,
+                                request,
+                                &request_data,
+                            )
+                            .await
+                        }
+                        BackendTypes::Triton(c) => {
+                            triton_routes::chat::compat_chat_completions(
+                                c.clone(),
+                                request,
+                                &mut request_data,
+                            )
+                            .await
+                        }
+                    };
+
+                    Ok::<Response, AiRouterError<String>>(response)
                 }
-            }
+            })
+            .await;
+            matched.set_response_header(&mut response);
+
+            return Ok(response);
         }
     }
 
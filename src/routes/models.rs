@@ -2,43 +2,72 @@ use std::sync::Arc;
 
 use axum::extract::State as AxumState;
 use axum::response::{IntoResponse, Response};
-use axum::Json;
-use openai_dive::v1::resources::model::{ListModelResponse, Model};
+use axum::{Extension, Json};
+use openai_dive::v1::resources::model::Model;
+use serde::Serialize;
 use tracing::instrument;
 
+use crate::auth::ApiKeyAccess;
+use crate::config::AiRouterModelType;
 use crate::errors::AiRouterError;
+use crate::models::is_pattern;
 use crate::state::State;
 
+/// Model object in `/v1/models` responses, with the type of the model
+///
+/// Models configured for multiple model types are listed once per model type.
+#[derive(Debug, Serialize)]
+struct AiRouterModelObject {
+    #[serde(flatten)]
+    model: Model,
+    model_type: AiRouterModelType,
+}
+
+#[derive(Debug, Serialize)]
+struct AiRouterModelList {
+    data: Vec<AiRouterModelObject>,
+    object: String,
+}
+
 #[instrument(skip(state))]
-pub async fn get(AxumState(state): AxumState<Arc<State>>) -> Response {
-    let mut model_names: Vec<String> = Vec::new();
+pub async fn get(
+    AxumState(state): AxumState<Arc<State>>,
+    Extension(api_key): Extension<ApiKeyAccess>,
+) -> Response {
+    let mut models: Vec<(String, AiRouterModelType)> = Vec::new();
 
     let model_types = state.config.models.keys();
     for model_type in model_types {
-        let Some(models) = state.config.models.get(model_type) else {
+        let Some(models_of_type) = state.config.models.get(model_type) else {
             return AiRouterError::InternalServerError::<String>(format!(
                 "failed to get models of type {model_type:?}"
             ))
             .into_response();
         };
-        for model in models.keys() {
-            model_names.push(model.clone());
+        for model in models_of_type.keys() {
+            // glob patterns are not model names clients can use as-is
+            if is_pattern(model) || !api_key.can_use(model_type, model) {
+                continue;
+            }
+            models.push((model.clone(), model_type.clone()));
         }
     }
 
-    let mut response: ListModelResponse = ListModelResponse {
+    let mut response = AiRouterModelList {
         data: Vec::new(),
         object: String::from("list"),
     };
 
-    for model_name in model_names {
+    for (model_name, model_type) in models {
         let model = Model {
             id: model_name,
             created: Some(1_700_000_000),
             object: String::from("model"),
             owned_by: String::from("original owners"),
         };
-        response.data.push(model);
+        response
+            .data
+            .push(AiRouterModelObject { model, model_type });
     }
 
     Json(response).into_response()
@@ -1,6 +1,7 @@
 use std::sync::Arc;
 
 use axum::extract::DefaultBodyLimit;
+use axum::middleware;
 use axum::response::IntoResponse;
 use axum::routing::{get, post};
 use axum::Router;
@@ -10,6 +11,8 @@ use tokio::net::TcpListener;
 use tokio::signal::unix::{signal, SignalKind};
 use tower_http::limit::RequestBodyLimitLayer;
 
+use crate::auth;
+use crate::backend::health;
 use crate::config::AiRouterConfigFile;
 use crate::errors::AiRouterError;
 use crate::routes;
@@ -24,22 +27,40 @@ use crate::state::State;
 pub async fn run_server(config_file: &AiRouterConfigFile) -> anyhow::Result<()> {
     let (prometheus_layer, metric_handle) = PrometheusMetricLayer::pair();
 
-    let state = State::new(config_file).await;
+    let state = Arc::new(State::new(config_file)?);
+    health::spawn_health_checks(&state.backends, config_file);
+    let auth_layer = middleware::from_fn_with_state(state.clone(), auth::require_api_key);
 
-    let app = Router::new()
+    let v1_router = Router::new()
         .route("/v1/audio/speech", post(routes::audio::speech))
         .route(
             "/v1/audio/transcriptions",
             post(routes::audio::transcriptions),
         )
+        .route("/v1/audio/translations", post(routes::audio::translations))
         .route("/v1/chat/completions", post(routes::chat::completion))
         .route("/v1/completions", post(routes::completions::completion))
         .route("/v1/embeddings", post(routes::embeddings::embed))
         .route("/v1/models", get(routes::get))
-        .route("/health_check", get(routes::health_check))
-        .route("/metrics", get(|| async move { metric_handle.render() }))
+        .route_layer(auth_layer.clone());
+
+    let mut health_check_router = Router::new().route("/health_check", get(routes::health_check));
+    if config_file.daemon.protect_health_check.unwrap_or(false) {
+        health_check_router = health_check_router.route_layer(auth_layer.clone());
+    }
+
+    let mut metrics_router =
+        Router::new().route("/metrics", get(|| async move { metric_handle.render() }));
+    if config_file.daemon.protect_metrics.unwrap_or(false) {
+        metrics_router = metrics_router.route_layer(auth_layer);
+    }
+
+    let app = Router::new()
+        .merge(v1_router)
+        .merge(health_check_router)
+        .merge(metrics_router)
         .fallback(fallback)
-        .with_state(Arc::new(state))
+        .with_state(state)
         .layer(prometheus_layer)
         .layer(OtelInResponseLayer)
         .layer(OtelAxumLayer::default())
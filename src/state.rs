@@ -1,6 +1,7 @@
 use crate::{
-    backend::{Backend, Backends},
+    backend::{selector::BackendSelector, Backend, Backends},
     config::AiRouterConfigFile,
+    templates::PromptTemplates,
     tokenizers::Tokenizers,
 };
 
@@ -14,18 +15,28 @@ pub enum BackendTypes<O, T> {
 pub struct State {
     pub backends: Backends,
     pub config: AiRouterConfigFile,
+    pub selector: BackendSelector,
+    pub templates: PromptTemplates,
     pub tokenizers: Tokenizers,
 }
 
 impl State {
-    pub async fn new(config_file: &AiRouterConfigFile) -> Self {
-        let backends = Backend::init(config_file).await;
+    /// Initialize the backends, templates and tokenizers of the models
+    ///
+    /// # Errors
+    /// - when a prompt template cannot be loaded
+    pub fn new(config_file: &AiRouterConfigFile) -> anyhow::Result<Self> {
+        let backends = Backend::init(config_file);
+        let selector = BackendSelector::new(&config_file.models);
+        let templates = PromptTemplates::new(config_file)?;
         let tokenizers = Tokenizers::new(&config_file.models);
 
-        Self {
+        Ok(Self {
             backends,
             config: config_file.clone(),
+            selector,
+            templates,
             tokenizers,
-        }
+        })
     }
 }
@@ -0,0 +1,440 @@
+//! Prompt templates turning chat messages into the prompt of Triton chat models
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use minijinja::{context, Environment, Error, ErrorKind};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::config::AiRouterConfigFile;
+use crate::errors::AiRouterError;
+
+/// `ChatML` with tools in the format of Hermes and Qwen models
+const CHATML: &str = concat!(
+    "{% if tools %}<|im_start|>system\n{% if messages[0].role == 'system' %}{{ messages[0].content }}\n\n{% endif %}",
+    "# Tools\n\nYou may call one or more functions to assist with the user query.\n\n",
+    "You are provided with function signatures within <tools></tools> XML tags:\n<tools>",
+    "{% for tool in tools %}\n{{ tool | tojson }}{% endfor %}\n</tools>\n\n",
+    "For each function call, return a json object with function name and arguments within <tool_call></tool_call> XML tags:\n",
+    "<tool_call>\n{\"name\": <function-name>, \"arguments\": <args-json-object>}\n</tool_call><|im_end|>\n{% endif %}",
+    "{% for message in messages %}",
+    "{% if message.role == 'system' and loop.first and tools %}",
+    "{% elif message.role == 'tool' %}<|im_start|>user\n<tool_response>\n{{ message.content }}\n</tool_response><|im_end|>\n",
+    "{% else %}<|im_start|>{{ message.role }}\n{{ message.content }}",
+    "{% for tool_call in message.tool_calls or [] %}{% if message.content or not loop.first %}\n{% endif %}",
+    "<tool_call>\n{{ {'name': tool_call.function.name, 'arguments': tool_call.function.arguments} | tojson }}\n</tool_call>",
+    "{% endfor %}<|im_end|>\n{% endif %}",
+    "{% endfor %}",
+    "{% if add_generation_prompt %}<|im_start|>assistant\n{% endif %}",
+);
+
+const LLAMA2: &str = "{% if messages[0].role == 'system' %}{% set system = messages[0].content %}{% set messages = messages[1:] %}{% endif %}{% for message in messages %}{% if message.role == 'user' %}<s>[INST] {% if loop.first and system %}<<SYS>>\n{{ system }}\n<</SYS>>\n\n{% endif %}{{ message.content | trim }} [/INST]{% elif message.role == 'assistant' %} {{ message.content | trim }} </s>{% endif %}{% endfor %}";
+
+/// Llama 3 with JSON based tool calling of Llama 3.1
+const LLAMA3: &str = concat!(
+    "<|begin_of_text|>",
+    "{% if tools %}<|start_header_id|>system<|end_header_id|>\n\n",
+    "{% if messages[0].role == 'system' %}{{ messages[0].content | trim }}\n\n{% endif %}",
+    "You have access to the following functions. To call a function, respond with a JSON object of the form ",
+    "{\"name\": function name, \"parameters\": dictionary of argument name and its value}. Do not use variables.\n\n",
+    "{% for tool in tools %}{{ tool | tojson }}\n\n{% endfor %}<|eot_id|>{% endif %}",
+    "{% for message in messages %}",
+    "{% if not (message.role == 'system' and loop.first and tools) %}",
+    "<|start_header_id|>{{ 'ipython' if message.role == 'tool' else message.role }}<|end_header_id|>\n\n",
+    "{% if message.tool_calls %}",
+    "{% for tool_call in message.tool_calls %}{{ {'name': tool_call.function.name, 'parameters': tool_call.function.arguments} | tojson }}{% endfor %}",
+    "{% else %}{{ message.content | trim }}{% endif %}<|eot_id|>",
+    "{% endif %}",
+    "{% endfor %}",
+    "{% if add_generation_prompt %}<|start_header_id|>assistant<|end_header_id|>\n\n{% endif %}",
+);
+
+const MISTRAL: &str = "{% if messages[0].role == 'system' %}{% set system = messages[0].content %}{% set messages = messages[1:] %}{% endif %}<s>{% for message in messages %}{% if message.role == 'user' %}[INST] {% if loop.first and system %}{{ system }}\n\n{% endif %}{{ message.content | trim }} [/INST]{% elif message.role == 'assistant' %}{{ message.content | trim }}</s>{% endif %}{% endfor %}";
+
+/// Templates selected by setting `prompt_format` of a model to their name
+const BUILTIN_TEMPLATES: [(&str, &str); 4] = [
+    ("chatml", CHATML),
+    ("llama2", LLAMA2),
+    ("llama3", LLAMA3),
+    ("mistral", MISTRAL),
+];
+
+/// Check if a `prompt_format` is the name of a built-in template
+pub fn is_builtin(prompt_format: &str) -> bool {
+    BUILTIN_TEMPLATES
+        .iter()
+        .any(|(name, _)| *name == prompt_format)
+}
+
+/// Path of the template file for a `prompt_format` that is not a built-in template, relative
+/// paths are resolved against `template_dir`
+pub fn template_path(template_dir: Option<&str>, prompt_format: &str) -> PathBuf {
+    match template_dir {
+        Some(template_dir) => Path::new(template_dir).join(prompt_format),
+        None => PathBuf::from(prompt_format),
+    }
+}
+
+/// Chat message as seen by templates
+///
+/// Tool calls are in the `OpenAI` format, with the arguments as object instead of a JSON string
+/// as expected by most chat templates.
+#[derive(Clone, Debug, Serialize)]
+pub struct PromptMessage {
+    pub role: &'static str,
+    pub content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+/// Compiled template of a `prompt_format`
+#[derive(Clone, Debug)]
+pub struct PromptTemplate {
+    environment: Arc<Environment<'static>>,
+    name: String,
+    bos_token: String,
+    eos_token: String,
+    /// The template references `tools`, templates without it drop tools and tool messages
+    supports_tools: bool,
+}
+
+impl PromptTemplate {
+    /// Render the prompt for `messages` and the `tools` the model can call, ending with the start
+    /// of an assistant message
+    ///
+    /// # Errors
+    /// `AiRouterError::BadRequestError` when the template rejects the messages, e.g. because
+    /// roles do not alternate, or when there are tools or tool messages but the template does
+    /// not support tools
+    pub fn render(
+        &self,
+        messages: &[PromptMessage],
+        tools: Option<&[Value]>,
+    ) -> Result<String, AiRouterError<String>> {
+        if !self.supports_tools
+            && (tools.is_some()
+                || messages
+                    .iter()
+                    .any(|m| m.role == "tool" || m.tool_calls.is_some()))
+        {
+            return Err(AiRouterError::BadRequestError(format!(
+                "prompt template {} does not support tools",
+                self.name
+            )));
+        }
+
+        let template = self.environment.get_template(&self.name)?;
+
+        template
+            .render(context! {
+                messages => messages,
+                tools => tools,
+                add_generation_prompt => true,
+                bos_token => self.bos_token,
+                eos_token => self.eos_token,
+            })
+            .map_err(|e| {
+                AiRouterError::BadRequestError(format!(
+                    "failed to apply prompt template {}: {e}",
+                    self.name
+                ))
+            })
+    }
+}
+
+#[derive(Debug)]
+pub struct PromptTemplates(HashMap<String, PromptTemplate>);
+
+impl PromptTemplates {
+    /// Compile the templates for the `prompt_format` of all models
+    ///
+    /// A `prompt_format` that is not the name of a built-in template is a path to a Jinja template
+    /// file, or to a `tokenizer_config.json` with a `chat_template`.
+    ///
+    /// # Errors
+    /// - when a template file cannot be read or has no `chat_template`
+    /// - when a template does not compile
+    pub fn new(config: &AiRouterConfigFile) -> Result<Self> {
+        let mut environment = Environment::new();
+        environment
+            .set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+        environment.add_function("raise_exception", raise_exception);
+
+        let mut tokens: HashMap<String, (String, String)> = HashMap::new();
+
+        for models in config.models.values() {
+            for (model_name, model) in models {
+                let Some(prompt_format) = &model.prompt_format else {
+                    continue;
+                };
+                if tokens.contains_key(prompt_format) {
+                    continue;
+                }
+
+                let loaded = if let Some((_, source)) = BUILTIN_TEMPLATES
+                    .iter()
+                    .find(|(name, _)| *name == prompt_format.as_str())
+                {
+                    Ok(ChatTemplate {
+                        source: String::from(*source),
+                        ..Default::default()
+                    })
+                } else {
+                    load_template(&template_path(
+                        config.daemon.template_dir.as_deref(),
+                        prompt_format,
+                    ))
+                };
+
+                let template = loaded
+                    .and_then(|template| {
+                        environment
+                            .add_template_owned(prompt_format.clone(), template.source)
+                            .map_err(anyhow::Error::from)?;
+                        Ok((template.bos_token, template.eos_token))
+                    })
+                    .with_context(|| {
+                        format!(
+                            "failed to load prompt_format `{prompt_format}` of model `{model_name}`"
+                        )
+                    })?;
+
+                tokens.insert(prompt_format.clone(), template);
+            }
+        }
+
+        let environment = Arc::new(environment);
+        let templates = tokens
+            .into_iter()
+            .map(|(name, (bos_token, eos_token))| {
+                let supports_tools = environment
+                    .get_template(&name)?
+                    .undeclared_variables(false)
+                    .contains("tools");
+                let template = PromptTemplate {
+                    environment: environment.clone(),
+                    name: name.clone(),
+                    bos_token,
+                    eos_token,
+                    supports_tools,
+                };
+                Ok((name, template))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self(templates))
+    }
+
+    pub fn get(&self, prompt_format: &str) -> Option<PromptTemplate> {
+        self.0.get(prompt_format).cloned()
+    }
+}
+
+#[derive(Debug, Default)]
+struct ChatTemplate {
+    source: String,
+    bos_token: String,
+    eos_token: String,
+}
+
+/// Load a Jinja template file, or the `chat_template` of a `tokenizer_config.json`
+fn load_template(path: &Path) -> Result<ChatTemplate> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("failed to read template file {}", path.display()))?;
+
+    let Ok(Value::Object(tokenizer_config)) = serde_json::from_str::<Value>(&content) else {
+        return Ok(ChatTemplate {
+            source: content,
+            ..Default::default()
+        });
+    };
+
+    // either a single template, or a list of named templates
+    let source = match tokenizer_config.get("chat_template") {
+        Some(Value::String(source)) => source.clone(),
+        Some(Value::Array(templates)) => templates
+            .iter()
+            .find(|t| t["name"] == "default")
+            .or_else(|| templates.first())
+            .and_then(|t| t["template"].as_str())
+            .map(String::from)
+            .context("no default chat_template")?,
+        _ => return Err(anyhow!("no chat_template in {}", path.display())),
+    };
+
+    Ok(ChatTemplate {
+        source,
+        bos_token: special_token(&tokenizer_config, "bos_token"),
+        eos_token: special_token(&tokenizer_config, "eos_token"),
+    })
+}
+
+/// Special tokens are strings or objects with the token in `content`
+fn special_token(tokenizer_config: &serde_json::Map<String, Value>, name: &str) -> String {
+    match tokenizer_config.get(name) {
+        Some(Value::String(token)) => token.clone(),
+        Some(token) => token["content"].as_str().unwrap_or_default().to_string(),
+        None => String::new(),
+    }
+}
+
+/// `raise_exception` of Hugging Face chat templates
+fn raise_exception(message: String) -> Result<String, Error> {
+    Err(Error::new(ErrorKind::InvalidOperation, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn messages() -> Vec<PromptMessage> {
+        let message = |role, content: &str| PromptMessage {
+            role,
+            content: String::from(content),
+            name: None,
+            tool_calls: None,
+            tool_call_id: None,
+        };
+
+        vec![
+            message("system", "Be brief."),
+            message("user", "Hi"),
+            message("assistant", "Hello!"),
+            message("user", "Bye"),
+        ]
+    }
+
+    fn template(prompt_format: &str) -> PromptTemplate {
+        let config = AiRouterConfigFile::parse(String::from("tests/ai-router.toml.prompt_formats"))
+            .expect("failed to load test config file");
+
+        PromptTemplates::new(&config)
+            .expect("failed to load templates")
+            .get(prompt_format)
+            .expect("template not loaded")
+    }
+
+    fn render(prompt_format: &str) -> String {
+        template(prompt_format)
+            .render(&messages(), None)
+            .expect("failed to render template")
+    }
+
+    #[test]
+    fn test_builtin_templates() {
+        assert_eq!(
+            render("chatml"),
+            "<|im_start|>system\nBe brief.<|im_end|>\n<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\nHello!<|im_end|>\n<|im_start|>user\nBye<|im_end|>\n<|im_start|>assistant\n"
+        );
+        assert_eq!(
+            render("llama2"),
+            "<s>[INST] <<SYS>>\nBe brief.\n<</SYS>>\n\nHi [/INST] Hello! </s><s>[INST] Bye [/INST]"
+        );
+        assert_eq!(
+            render("llama3"),
+            "<|begin_of_text|><|start_header_id|>system<|end_header_id|>\n\nBe brief.<|eot_id|><|start_header_id|>user<|end_header_id|>\n\nHi<|eot_id|><|start_header_id|>assistant<|end_header_id|>\n\nHello!<|eot_id|><|start_header_id|>user<|end_header_id|>\n\nBye<|eot_id|><|start_header_id|>assistant<|end_header_id|>\n\n"
+        );
+        assert_eq!(
+            render("mistral"),
+            "<s>[INST] Be brief.\n\nHi [/INST]Hello!</s>[INST] Bye [/INST]"
+        );
+    }
+
+    #[test]
+    fn test_chat_template() {
+        assert_eq!(
+            render("templates.test_chat_template"),
+            "<bos><start_of_turn>user\nBe brief.\n\nHi<end_of_turn>\n<start_of_turn>model\nHello!<end_of_turn>\n<start_of_turn>user\nBye<end_of_turn>\n<start_of_turn>model\n"
+        );
+
+        let result = template("templates.test_chat_template").render(&messages()[2..], None);
+        assert!(matches!(result, Err(AiRouterError::BadRequestError(_))));
+    }
+
+    #[test]
+    fn test_tools() {
+        let tools = [json!({
+            "type": "function",
+            "function": {
+                "name": "get_weather",
+                "description": "Get the weather",
+                "parameters": {"type": "object"}
+            }
+        })];
+        let mut messages = messages();
+        messages.truncate(2);
+        messages.push(PromptMessage {
+            role: "assistant",
+            content: String::new(),
+            name: None,
+            tool_calls: Some(vec![json!({
+                "id": "call_1",
+                "type": "function",
+                "function": {"name": "get_weather", "arguments": {"city": "Paris"}}
+            })]),
+            tool_call_id: None,
+        });
+        messages.push(PromptMessage {
+            role: "tool",
+            content: String::from("sunny"),
+            name: None,
+            tool_calls: None,
+            tool_call_id: Some(String::from("call_1")),
+        });
+
+        let prompt = template("chatml")
+            .render(&messages, Some(&tools))
+            .expect("failed to render template");
+        assert_eq!(
+            prompt,
+            concat!(
+                "<|im_start|>system\nBe brief.\n\n# Tools\n\nYou may call one or more functions to assist with the user query.\n\n",
+                "You are provided with function signatures within <tools></tools> XML tags:\n<tools>\n",
+                r#"{"function":{"description":"Get the weather","name":"get_weather","parameters":{"type":"object"}},"type":"function"}"#,
+                "\n</tools>\n\nFor each function call, return a json object with function name and arguments within <tool_call></tool_call> XML tags:\n",
+                "<tool_call>\n{\"name\": <function-name>, \"arguments\": <args-json-object>}\n</tool_call><|im_end|>\n",
+                "<|im_start|>user\nHi<|im_end|>\n",
+                "<|im_start|>assistant\n<tool_call>\n",
+                r#"{"arguments":{"city":"Paris"},"name":"get_weather"}"#,
+                "\n</tool_call><|im_end|>\n",
+                "<|im_start|>user\n<tool_response>\nsunny\n</tool_response><|im_end|>\n",
+                "<|im_start|>assistant\n"
+            )
+        );
+
+        let prompt = template("llama3")
+            .render(&messages, Some(&tools))
+            .expect("failed to render template");
+        assert_eq!(
+            prompt,
+            concat!(
+                "<|begin_of_text|><|start_header_id|>system<|end_header_id|>\n\nBe brief.\n\n",
+                "You have access to the following functions. To call a function, respond with a JSON object of the form ",
+                "{\"name\": function name, \"parameters\": dictionary of argument name and its value}. Do not use variables.\n\n",
+                r#"{"function":{"description":"Get the weather","name":"get_weather","parameters":{"type":"object"}},"type":"function"}"#,
+                "\n\n<|eot_id|><|start_header_id|>user<|end_header_id|>\n\nHi<|eot_id|>",
+                "<|start_header_id|>assistant<|end_header_id|>\n\n",
+                r#"{"name":"get_weather","parameters":{"city":"Paris"}}"#,
+                "<|eot_id|><|start_header_id|>ipython<|end_header_id|>\n\nsunny<|eot_id|>",
+                "<|start_header_id|>assistant<|end_header_id|>\n\n"
+            )
+        );
+
+        for prompt_format in ["llama2", "mistral"] {
+            let result = template(prompt_format).render(&messages[..2], Some(&tools));
+            assert!(matches!(result, Err(AiRouterError::BadRequestError(_))));
+
+            let result = template(prompt_format).render(&messages, None);
+            assert!(matches!(result, Err(AiRouterError::BadRequestError(_))));
+        }
+    }
+}
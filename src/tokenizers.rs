@@ -1,15 +1,16 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use tokenizers::tokenizer::Tokenizer;
 
 use crate::config::AiRouterModels;
 
 #[derive(Debug)]
-pub struct Tokenizers(HashMap<String, Tokenizer>);
+pub struct Tokenizers(HashMap<String, Arc<Tokenizer>>);
 
 impl Tokenizers {
     pub fn new(models: &AiRouterModels) -> Self {
-        let mut tokenizers: HashMap<String, Tokenizer> = HashMap::new();
+        let mut tokenizers: HashMap<String, Arc<Tokenizer>> = HashMap::new();
 
         for models in models.values() {
             for (model_name, model) in models {
@@ -23,7 +24,7 @@ impl Tokenizers {
                             }
                         };
 
-                        tokenizers.insert(String::from(hf_model_name), tokenizer);
+                        tokenizers.insert(String::from(hf_model_name), Arc::new(tokenizer));
                     }
                 }
             }
@@ -32,7 +33,7 @@ impl Tokenizers {
         Self(tokenizers)
     }
 
-    pub fn get(tokenizers: &Self, name: &str) -> Option<Tokenizer> {
+    pub fn get(tokenizers: &Self, name: &str) -> Option<Arc<Tokenizer>> {
         tokenizers.0.get(name).cloned()
     }
 }
@@ -1,56 +1,41 @@
-use std::marker::PhantomData;
+use std::path::Path;
 use std::str;
 use std::str::Utf8Error;
-use std::{fmt, path::Path};
 
 use bytes::{Buf, Bytes};
-use serde::{de, Deserialize, Deserializer};
 
 use crate::errors::AiRouterError;
 
-pub fn string_or_seq_string<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    struct StringOrVec(PhantomData<Vec<String>>);
-
-    impl<'de> de::Visitor<'de> for StringOrVec {
-        type Value = Vec<String>;
-
-        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-            formatter.write_str("string or list of strings")
-        }
-
-        fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
-        where
-            E: de::Error,
-        {
-            Ok(vec![value.to_owned()])
-        }
-
-        fn visit_seq<S>(self, visitor: S) -> Result<Self::Value, S::Error>
-        where
-            S: de::SeqAccess<'de>,
-        {
-            Deserialize::deserialize(de::value::SeqAccessDeserializer::new(visitor))
-        }
-    }
-
-    deserializer.deserialize_any(StringOrVec(PhantomData))
+pub fn deserialize_bytes_tensor(encoded_tensor: Vec<u8>) -> Result<Vec<String>, Utf8Error> {
+    split_bytes_tensor(encoded_tensor)
+        .iter()
+        .map(|slice| str::from_utf8(slice).map(str::to_string))
+        .collect()
 }
 
-pub fn deserialize_bytes_tensor(encoded_tensor: Vec<u8>) -> Result<Vec<String>, Utf8Error> {
+/// Split a BYTES tensor into its elements without decoding them, e.g. when an element may end in
+/// the middle of a multi-byte UTF-8 character
+///
+/// A truncated element at the end of the tensor is left out.
+pub fn split_bytes_tensor(encoded_tensor: Vec<u8>) -> Vec<Bytes> {
     let mut bytes = Bytes::from(encoded_tensor);
-    let mut strs = Vec::new();
+    let mut slices = Vec::new();
     while bytes.has_remaining() {
+        if bytes.remaining() < 4 {
+            tracing::warn!("BYTES tensor ends with a truncated element length");
+            break;
+        }
         let len = bytes.get_u32_le() as usize;
-        if len <= bytes.remaining() {
-            let slice = bytes.split_to(len);
-            let s = str::from_utf8(&slice)?;
-            strs.push(s.to_string());
+        if len > bytes.remaining() {
+            tracing::warn!(
+                "BYTES tensor element of {len} bytes is truncated to {} bytes",
+                bytes.remaining()
+            );
+            break;
         }
+        slices.push(bytes.split_to(len));
     }
-    Ok(strs)
+    slices
 }
 
 pub fn get_file_extension(filename: &str) -> Result<&str, AiRouterError<String>> {
@@ -72,7 +57,7 @@ mod tests {
 
     use serde::Deserialize;
 
-    use super::deserialize_bytes_tensor;
+    use super::{deserialize_bytes_tensor, split_bytes_tensor};
 
     #[derive(Deserialize)]
     struct UtilsTestData {
@@ -98,4 +83,18 @@ mod tests {
 
         assert_eq!(test_result, test_data.output);
     }
+
+    #[test]
+    fn test_split_bytes_tensor_truncated() {
+        let mut tensor = [2u32.to_le_bytes().as_slice(), b"ok"].concat();
+        assert_eq!(split_bytes_tensor(tensor.clone()), vec!["ok"]);
+
+        // element length cut short
+        tensor.extend_from_slice(&[5, 0]);
+        assert_eq!(split_bytes_tensor(tensor.clone()), vec!["ok"]);
+
+        // element longer than the rest of the tensor
+        tensor.extend_from_slice(&[0, 0, b'a']);
+        assert_eq!(split_bytes_tensor(tensor), vec!["ok"]);
+    }
 }